@@ -0,0 +1,108 @@
+//! Checks that the live feed reports a donation's changes in order. It subscribes to `GET /feed`,
+//! creates, edits and deletes a food, then expects created, updated and deleted for it, with every
+//! event id larger than the one before.
+//!
+//!     AVANZO_TOKEN=<access token of a verified user> cargo run --example feed_client
+//!
+//! AVANZO_URL points it at the server, http://127.0.0.1:8080 by default.
+
+use awc::Client;
+use chrono::{Duration as ChronoDuration, Utc};
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use std::env;
+use std::error::Error;
+use std::time::Duration;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+struct Event {
+    id: u64,
+    kind: String,
+    data: Value,
+}
+
+// splits the complete events off the front of the buffer, heartbeats are comments and get dropped
+fn take_events(buffer: &mut Vec<u8>) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    while let Some(end) = buffer.windows(2).position(|pair| pair == b"\n\n") {
+        let block: Vec<u8> = buffer.drain(..end + 2).collect();
+        let block = String::from_utf8_lossy(&block);
+        let (mut id, mut kind, mut data) = (None, None, None);
+        for line in block.lines() {
+            if let Some(value) = line.strip_prefix("id: ") {
+                id = value.parse().ok();
+            } else if let Some(value) = line.strip_prefix("event: ") {
+                kind = Some(value.to_string());
+            } else if let Some(value) = line.strip_prefix("data: ") {
+                data = serde_json::from_str(value).ok();
+            }
+        }
+        match (id, kind, data) {
+            (Some(id), Some(kind), Some(data)) => events.push(Event { id, kind, data }),
+            (_, Some(kind), _) if kind == "resync" => return Err("the feed fell behind and dropped events".to_string()),
+            _ => {}
+        }
+    }
+    Ok(events)
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let base = env::var("AVANZO_URL").unwrap_or("http://127.0.0.1:8080".to_string());
+    let token = env::var("AVANZO_TOKEN").map_err(|_| "AVANZO_TOKEN not set")?;
+    let client = Client::default();
+
+    // subscribed before anything changes, so none of our events can be missed
+    let mut feed = client.get(format!("{}/feed", base)).send().await.map_err(|err| err.to_string())?;
+    assert!(feed.status().is_success(), "couldn't open the feed: {}", feed.status());
+
+    let start = Utc::now() + ChronoDuration::hours(1);
+    let mut food = json!({
+        "title": "feed client test",
+        "description": "created by examples/feed_client, safe to delete",
+        "is_free": true,
+        "pickup_start": start.to_rfc3339(),
+        "pickup_end": (start + ChronoDuration::hours(2)).to_rfc3339(),
+        "pickup_address": "feed client test",
+        // the server takes the user from the token
+        "user_id": 0,
+        "latitude": 45.4642,
+        "longitude": 9.19
+    });
+    let mut created = client.post(format!("{}/foods", base)).bearer_auth(&token).send_json(&food).await.map_err(|err| err.to_string())?;
+    let body: Value = created.json().await.map_err(|err| err.to_string())?;
+    let food_id = body["data"]["id"].as_i64().ok_or_else(|| format!("food wasn't created: {}", body))?;
+
+    food["food_id"] = json!(food_id);
+    food["title"] = json!("feed client test, edited");
+    let edited = client.patch(format!("{}/donations", base)).bearer_auth(&token).send_json(&food).await.map_err(|err| err.to_string())?;
+    assert!(edited.status().is_success(), "couldn't edit the food: {}", edited.status());
+
+    let deleted = client.delete(format!("{}/foods/{}", base, food_id)).bearer_auth(&token).send().await.map_err(|err| err.to_string())?;
+    assert!(deleted.status().is_success(), "couldn't delete the food: {}", deleted.status());
+
+    // other people's changes can show up in between, only ours are checked by kind but every id has to grow
+    let mut buffer = Vec::new();
+    let mut last_id = 0;
+    let mut ours = Vec::new();
+    while ours.len() < 3 {
+        let chunk = match tokio::time::timeout(WAIT, feed.next()).await {
+            Ok(Some(chunk)) => chunk.map_err(|err| err.to_string())?,
+            Ok(None) => return Err("the feed closed early".into()),
+            Err(_) => return Err(format!("timed out waiting for events, got {:?}", ours).into()),
+        };
+        buffer.extend_from_slice(&chunk);
+        for event in take_events(&mut buffer)? {
+            assert!(event.id > last_id, "event {} arrived after event {}", event.id, last_id);
+            last_id = event.id;
+            if event.data["food"]["id"].as_i64() == Some(food_id) {
+                ours.push(event.kind);
+            }
+        }
+    }
+
+    assert_eq!(ours, ["created", "updated", "deleted"], "events for food {} arrived out of order", food_id);
+    println!("feed events for food {} arrived in order: {}", food_id, ours.join(", "));
+    Ok(())
+}
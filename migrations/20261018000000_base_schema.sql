@@ -0,0 +1,40 @@
+-- the tables the app started out with, as they were before these migrations.
+-- existing databases already have them, this is for fresh ones such as the test databases
+CREATE TABLE IF NOT EXISTS users (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    email VARCHAR(255) NOT NULL,
+    password_hash VARCHAR(255) NOT NULL,
+    first_name VARCHAR(100) NULL,
+    last_name VARCHAR(100) NULL,
+    num_of_food_added INT NULL DEFAULT 0,
+    num_of_food_taken INT NULL DEFAULT 0,
+    profile_image LONGBLOB NULL,
+    email_verified TINYINT(1) NULL DEFAULT 0,
+    code_pass VARCHAR(10) NULL,
+    has_reserve TINYINT(1) NOT NULL DEFAULT 0,
+    is_active TINYINT(1) NOT NULL DEFAULT 1,
+    KEY idx_users_email (email)
+);
+
+CREATE TABLE IF NOT EXISTS foods (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    title VARCHAR(255) NULL,
+    description TEXT NULL,
+    is_free TINYINT(1) NULL,
+    pickup_time VARCHAR(255) NULL,
+    user_id INT NULL,
+    image LONGBLOB NULL,
+    pickup_address VARCHAR(255) NULL,
+    status VARCHAR(20) NULL DEFAULT 'active',
+    CONSTRAINT fk_foods_user FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE TABLE IF NOT EXISTS reservations (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    user_id INT NOT NULL,
+    food_id INT NOT NULL,
+    reserved_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    status VARCHAR(20) NULL DEFAULT 'active',
+    CONSTRAINT fk_reservations_user FOREIGN KEY (user_id) REFERENCES users (id),
+    CONSTRAINT fk_reservations_food FOREIGN KEY (food_id) REFERENCES foods (id) ON DELETE CASCADE
+);
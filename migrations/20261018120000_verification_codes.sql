@@ -0,0 +1,14 @@
+CREATE TABLE verification_codes (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    user_id INT NOT NULL,
+    code_hash CHAR(64) NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    consumed_at TIMESTAMP NULL,
+    KEY idx_verification_codes_user (user_id, consumed_at),
+    CONSTRAINT fk_verification_codes_user FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+-- codes used to live in clear text on the user row
+ALTER TABLE users DROP COLUMN code_pass;
@@ -0,0 +1,8 @@
+-- 'active' meant the reservation was holding the food, which is now 'confirmed'
+UPDATE reservations SET status = 'confirmed' WHERE status = 'active';
+
+ALTER TABLE reservations
+    MODIFY status VARCHAR(20) NOT NULL DEFAULT 'requested',
+    ADD COLUMN status_changed_at TIMESTAMP NULL;
+
+UPDATE foods SET status = 'active' WHERE status IS NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE reservations
+    ADD COLUMN cancelled_by VARCHAR(20) NULL,
+    ADD COLUMN cancel_reason VARCHAR(500) NULL;
@@ -0,0 +1,8 @@
+ALTER TABLE reservations
+    ADD COLUMN expires_at TIMESTAMP NULL,
+    ADD KEY idx_reservations_due (status, expires_at);
+
+UPDATE reservations SET expires_at = DATE_ADD(reserved_at, INTERVAL 24 HOUR)
+WHERE status IN ('requested', 'confirmed');
+
+ALTER TABLE users ADD COLUMN no_show_count INT NOT NULL DEFAULT 0;
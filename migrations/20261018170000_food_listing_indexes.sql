@@ -0,0 +1,3 @@
+CREATE INDEX idx_foods_status_id ON foods (status, id);
+CREATE INDEX idx_foods_user_id ON foods (user_id, id);
+CREATE INDEX idx_reservations_user_id ON reservations (user_id, id);
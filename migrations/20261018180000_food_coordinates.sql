@@ -0,0 +1,4 @@
+ALTER TABLE foods
+    ADD COLUMN latitude DOUBLE NULL,
+    ADD COLUMN longitude DOUBLE NULL,
+    ADD KEY idx_foods_coordinates (latitude, longitude);
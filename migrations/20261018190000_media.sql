@@ -0,0 +1,13 @@
+CREATE TABLE media (
+    media_key CHAR(64) NOT NULL PRIMARY KEY,
+    owner_id INT NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    byte_size BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    KEY idx_media_owner (owner_id),
+    CONSTRAINT fk_media_owner FOREIGN KEY (owner_id) REFERENCES users (id)
+);
+
+ALTER TABLE foods ADD COLUMN image_key CHAR(64) NULL;
+ALTER TABLE users ADD COLUMN profile_image_key CHAR(64) NULL;
+-- the BLOB columns stay until `migrate-images` has emptied them
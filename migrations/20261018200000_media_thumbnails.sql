@@ -0,0 +1 @@
+ALTER TABLE media ADD COLUMN thumbnail_key CHAR(64) NULL;
@@ -0,0 +1,24 @@
+ALTER TABLE foods
+    ADD COLUMN pickup_start DATETIME NULL,
+    ADD COLUMN pickup_end DATETIME NULL,
+    ADD COLUMN pickup_timezone VARCHAR(6) NULL;
+
+-- best effort over the old free text. the app wrote 'YYYY-MM-DD HH:MM' (sometimes with a T or seconds),
+-- a few clients sent 'DD/MM/YYYY HH:MM'. those times were server time (UTC) and had no end,
+-- so a parsed start gets a two hour window. anything else stays NULL and keeps its text below
+UPDATE foods
+SET pickup_start = STR_TO_DATE(REPLACE(LEFT(pickup_time, 16), 'T', ' '), '%Y-%m-%d %H:%i')
+WHERE pickup_time REGEXP '^[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])[ T]([01][0-9]|2[0-3]):[0-5][0-9]';
+
+UPDATE foods
+SET pickup_start = STR_TO_DATE(LEFT(pickup_time, 16), '%d/%m/%Y %H:%i')
+WHERE pickup_start IS NULL
+AND pickup_time REGEXP '^(0[1-9]|[12][0-9]|3[01])/(0[1-9]|1[0-2])/[0-9]{4} ([01][0-9]|2[0-3]):[0-5][0-9]';
+
+UPDATE foods
+SET pickup_end = DATE_ADD(pickup_start, INTERVAL 2 HOUR), pickup_timezone = '+00:00'
+WHERE pickup_start IS NOT NULL;
+
+ALTER TABLE foods RENAME COLUMN pickup_time TO legacy_pickup_time;
+
+CREATE INDEX idx_foods_status_pickup ON foods (status, pickup_start, id);
@@ -0,0 +1,20 @@
+ALTER TABLE foods
+    ADD COLUMN category VARCHAR(32) NULL,
+    ADD COLUMN allergens_declared TINYINT(1) NOT NULL DEFAULT 0;
+
+CREATE INDEX idx_foods_status_category ON foods (status, category, id);
+
+CREATE TABLE food_dietary_tags (
+    food_id INT NOT NULL,
+    tag VARCHAR(32) NOT NULL,
+    PRIMARY KEY (food_id, tag),
+    INDEX idx_food_dietary_tags_tag (tag, food_id),
+    CONSTRAINT fk_food_dietary_tags_food FOREIGN KEY (food_id) REFERENCES foods (id) ON DELETE CASCADE
+);
+
+CREATE TABLE food_allergens (
+    food_id INT NOT NULL,
+    allergen VARCHAR(32) NOT NULL,
+    PRIMARY KEY (food_id, allergen),
+    CONSTRAINT fk_food_allergens_food FOREIGN KEY (food_id) REFERENCES foods (id) ON DELETE CASCADE
+);
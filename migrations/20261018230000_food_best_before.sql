@@ -0,0 +1,3 @@
+ALTER TABLE foods
+    ADD COLUMN best_before DATETIME NULL,
+    ADD KEY idx_foods_status_best_before (status, best_before);
@@ -0,0 +1,9 @@
+ALTER TABLE foods
+    ADD COLUMN quantity INT NOT NULL DEFAULT 1,
+    ADD COLUMN quantity_remaining INT NOT NULL DEFAULT 1,
+    ADD COLUMN max_per_user INT NULL;
+
+-- every existing food was a single portion, held ones have nothing left
+UPDATE foods SET quantity_remaining = 0 WHERE status IN ('reserved', 'picked_up');
+
+ALTER TABLE reservations ADD COLUMN quantity INT NOT NULL DEFAULT 1;
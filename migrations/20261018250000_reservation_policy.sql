@@ -0,0 +1,6 @@
+ALTER TABLE users ADD COLUMN is_organization TINYINT(1) NOT NULL DEFAULT 0;
+
+-- open reservations are counted from the reservations table now
+ALTER TABLE users DROP COLUMN has_reserve;
+
+CREATE INDEX idx_reservations_user_status ON reservations (user_id, status);
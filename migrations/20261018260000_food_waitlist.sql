@@ -0,0 +1,14 @@
+CREATE TABLE food_waitlist (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    food_id INT NOT NULL,
+    user_id INT NOT NULL,
+    quantity INT NOT NULL DEFAULT 1,
+    -- 'waiting' in line, 'offered' while the food is held for them
+    status VARCHAR(20) NOT NULL DEFAULT 'waiting',
+    offer_expires_at TIMESTAMP NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE KEY uq_food_waitlist_food_user (food_id, user_id),
+    KEY idx_food_waitlist_offers (status, offer_expires_at),
+    CONSTRAINT fk_food_waitlist_food FOREIGN KEY (food_id) REFERENCES foods (id) ON DELETE CASCADE,
+    CONSTRAINT fk_food_waitlist_user FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+ALTER TABLE foods ADD COLUMN requires_approval TINYINT(1) NOT NULL DEFAULT 0;
+
+CREATE INDEX idx_reservations_food_status ON reservations (food_id, status);
@@ -0,0 +1,16 @@
+ALTER TABLE reservations ADD COLUMN pickup_nonce VARCHAR(64) NULL;
+
+-- reservations confirmed before codes existed get one now
+UPDATE reservations SET pickup_nonce = SHA2(CONCAT(id, ':', RAND(), ':', NOW(6)), 256)
+WHERE status = 'confirmed';
+
+-- num_of_food_added now counts handed over donations, taken counts collected ones.
+-- both are rebuilt from the reservations so they start out in step
+UPDATE users u SET
+    num_of_food_added = (
+        SELECT COUNT(*) FROM reservations r INNER JOIN foods f ON f.id = r.food_id
+        WHERE f.user_id = u.id AND r.status = 'picked_up'
+    ),
+    num_of_food_taken = (
+        SELECT COUNT(*) FROM reservations r WHERE r.user_id = u.id AND r.status = 'picked_up'
+    );
@@ -0,0 +1,12 @@
+CREATE TABLE reservation_messages (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    reservation_id INT NOT NULL,
+    sender_id INT NOT NULL,
+    -- stored escaped, so it can be longer than the 1000 characters a message allows
+    body TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    read_at TIMESTAMP NULL,
+    KEY idx_reservation_messages_thread (reservation_id, id),
+    CONSTRAINT fk_reservation_messages_reservation FOREIGN KEY (reservation_id) REFERENCES reservations (id) ON DELETE CASCADE,
+    CONSTRAINT fk_reservation_messages_sender FOREIGN KEY (sender_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- the column only ever held the donor's offset from UTC (+02:00), never a zone name that knows about daylight saving
+ALTER TABLE foods RENAME COLUMN pickup_timezone TO pickup_utc_offset;
@@ -0,0 +1,2 @@
+-- every short code a donor tries against a reservation, counted so the six digits can't be run through
+ALTER TABLE reservations ADD COLUMN pickup_code_attempts INT NOT NULL DEFAULT 0;
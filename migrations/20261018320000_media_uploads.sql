@@ -0,0 +1,11 @@
+-- the same bytes are stored once, but everyone who uploaded them may use them
+CREATE TABLE media_uploads (
+    media_key CHAR(64) NOT NULL,
+    user_id INT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (media_key, user_id),
+    CONSTRAINT fk_media_uploads_media FOREIGN KEY (media_key) REFERENCES media (media_key) ON DELETE CASCADE,
+    CONSTRAINT fk_media_uploads_user FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+INSERT INTO media_uploads (media_key, user_id) SELECT media_key, owner_id FROM media;
@@ -0,0 +1,8 @@
+-- an offer from the waitlist now claims the portions it was made for instead of holding the whole food,
+-- so foods held for an offer give up those portions and go back on the listings for what's left
+UPDATE foods f
+INNER JOIN food_waitlist w ON w.food_id = f.id AND w.status = 'offered'
+SET f.quantity_remaining = GREATEST(f.quantity_remaining - w.quantity, 0)
+WHERE f.status = 'held';
+
+UPDATE foods SET status = IF(quantity_remaining = 0, 'reserved', 'active') WHERE status = 'held';
@@ -0,0 +1,4 @@
+-- num_of_food_added goes back to counting posted donations. the pickup codes migration had rebuilt it
+-- from handed over ones, so it is counted again from the foods table; donations deleted since are lost to it.
+-- num_of_food_taken keeps counting collected reservations and is left as it is
+UPDATE users u SET num_of_food_added = (SELECT COUNT(*) FROM foods f WHERE f.user_id = u.id);
@@ -0,0 +1,6 @@
+-- messages are kept as plain text now, clients escape them when they show them.
+-- bodies stored escaped before are unescaped, &amp; last so text like &lt; typed by a sender survives
+UPDATE reservation_messages SET body =
+    REPLACE(REPLACE(REPLACE(REPLACE(REPLACE(body, '&#39;', ''''), '&quot;', '"'), '&gt;', '>'), '&lt;', '<'), '&amp;', '&');
+
+ALTER TABLE reservation_messages MODIFY body TEXT NOT NULL COMMENT 'plain text as the sender wrote it';
@@ -0,0 +1,219 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::MySqlPool;
+use subtle::ConstantTimeEq;
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db::{get_food_owner, is_admin, is_session_active};
+use crate::errors::ApiError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const ACCESS_TOKEN_TTL_SECS: u64 = 15 * 60;
+pub const REFRESH_TOKEN_TTL_DAYS: i32 = 30;
+pub const PASSWORD_RESET_TTL_MINUTES: i32 = 30;
+// one reset mail per account in this long, however often someone asks
+pub const PASSWORD_RESET_COOLDOWN_SECS: i64 = 5 * 60;
+pub const VERIFY_CODE_TTL_MINUTES: i32 = 15;
+// wrong guesses a user gets across all their codes within the window, so asking for a new code doesn't reset them
+pub const VERIFY_CODE_MAX_ATTEMPTS: i32 = 5;
+pub const VERIFY_CODE_ATTEMPT_WINDOW_MINUTES: i32 = 60;
+pub const VERIFY_CODE_RESEND_COOLDOWN_SECS: i64 = 60;
+// tries a donor gets at the six digit pickup code of one reservation, the qr payload can't be guessed and isn't counted
+pub const PICKUP_CODE_MAX_ATTEMPTS: i32 = 5;
+
+pub struct Claims {
+    pub user_id: i32,
+    pub session_id: u64,
+    pub expires_at: u64,
+}
+
+static TOKEN_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+// read once at startup, so a missing secret stops the server instead of a worker mid request
+pub fn load_token_secret() -> Result<(), String> {
+    let secret = env::var("TOKEN_SECRET").map_err(|_| "TOKEN_SECRET not set".to_string())?;
+    if secret.is_empty() {
+        return Err("TOKEN_SECRET is empty".to_string());
+    }
+    let _ = TOKEN_SECRET.set(secret.into_bytes());
+    Ok(())
+}
+
+fn token_secret() -> &'static [u8] {
+    TOKEN_SECRET.get().expect("load_token_secret runs before the server starts")
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn sign(payload: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(token_secret()).expect("hmac accepts any key length");
+    mac.update(payload.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// access tokens look like base64(user_id:session_id:exp).base64(hmac)
+pub fn issue_access_token(user_id: i32, session_id: u64) -> String {
+    let payload = format!("{}:{}:{}", user_id, session_id, now_secs() + ACCESS_TOKEN_TTL_SECS);
+    format!("{}.{}", URL_SAFE_NO_PAD.encode(&payload), URL_SAFE_NO_PAD.encode(sign(&payload)))
+}
+
+pub fn verify_access_token(token: &str) -> Option<Claims> {
+    let (payload_part, sig_part) = token.split_once('.')?;
+    let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(payload_part).ok()?).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(sig_part).ok()?;
+
+    let mut mac = HmacSha256::new_from_slice(token_secret()).ok()?;
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+
+    let mut parts = payload.split(':');
+    let claims = Claims {
+        user_id: parts.next()?.parse().ok()?,
+        session_id: parts.next()?.parse().ok()?,
+        expires_at: parts.next()?.parse().ok()?,
+    };
+    if claims.expires_at <= now_secs() {
+        return None;
+    }
+    Some(claims)
+}
+
+// random url safe secret, used for refresh and password reset tokens
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// only the hash of a token is ever written to the db
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// six digits are quick to brute force from a plain hash, keyed with the server secret a leaked row is useless
+pub fn hash_verification_code(user_id: i32, code: &str) -> String {
+    sign(&format!("verify:{}:{}", user_id, code))
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// the pickup code and the qr token both come from the reservation id and a nonce picked when the
+// donor confirmed, so nothing secret is stored and a new nonce makes the old ones worthless
+const PICKUP_QR_PREFIX: &str = "avanzo-pickup:";
+
+fn pickup_payload(reservation_id: i32, nonce: &str) -> String {
+    format!("pickup:{}:{}", reservation_id, nonce)
+}
+
+pub fn pickup_code(reservation_id: i32, nonce: &str) -> String {
+    let signature = sign(&pickup_payload(reservation_id, nonce));
+    let number = u32::from_be_bytes([signature[0], signature[1], signature[2], signature[3]]) % 1_000_000;
+    format!("{:06}", number)
+}
+
+pub fn pickup_qr_payload(reservation_id: i32, nonce: &str) -> String {
+    let signature = sign(&pickup_payload(reservation_id, nonce));
+    format!("{}{}:{}", PICKUP_QR_PREFIX, reservation_id, URL_SAFE_NO_PAD.encode(signature))
+}
+
+fn verify_pickup_qr(reservation_id: i32, nonce: &str, payload: &str) -> Option<()> {
+    let (id, token) = payload.split_once(':')?;
+    if id.parse::<i32>().ok()? != reservation_id {
+        return None;
+    }
+    let signature = URL_SAFE_NO_PAD.decode(token).ok()?;
+    let mut mac = HmacSha256::new_from_slice(token_secret()).ok()?;
+    mac.update(pickup_payload(reservation_id, nonce).as_bytes());
+    mac.verify_slice(&signature).ok()
+}
+
+pub fn is_pickup_qr(submitted: &str) -> bool {
+    submitted.trim().starts_with(PICKUP_QR_PREFIX)
+}
+
+// takes either what the receiver reads out (the short code) or what the donor scans (the qr payload).
+// the code is compared in constant time so response times don't tell how many digits were right
+pub fn verify_pickup(reservation_id: i32, nonce: &str, submitted: &str) -> bool {
+    let submitted = submitted.trim();
+    match submitted.strip_prefix(PICKUP_QR_PREFIX) {
+        Some(payload) => verify_pickup_qr(reservation_id, nonce, payload).is_some(),
+        None => submitted.as_bytes().ct_eq(pickup_code(reservation_id, nonce).as_bytes()).into()
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    header.strip_prefix("Bearer ").map(|t| t.trim().to_string())
+}
+
+/// The user behind a valid access token whose session has not been revoked.
+pub struct AuthUser {
+    pub user_id: i32,
+    pub session_id: u64,
+}
+
+impl FromRequest for AuthUser {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let claims = bearer_token(req).and_then(|token| verify_access_token(&token));
+        let pool = req.app_data::<web::Data<MySqlPool>>().cloned();
+
+        Box::pin(async move {
+            let claims = claims.ok_or_else(|| ApiError::Unauthorized(format!("invalid or expired token")))?;
+            let pool = pool.ok_or_else(|| ApiError::Internal(format!("database pool not configured")))?;
+            match is_session_active(&pool, claims.session_id, claims.user_id).await {
+                Ok(true) => Ok(AuthUser { user_id: claims.user_id, session_id: claims.session_id }),
+                Ok(false) => Err(ApiError::Unauthorized(format!("session has ended"))),
+                Err(err) => Err(ApiError::db("there was an error checking session", err))
+            }
+        })
+    }
+}
+
+/// Something a caller can only touch if it belongs to them.
+pub enum Owned {
+    User(i32),
+    Food(i32),
+}
+
+// Ok when the authenticated user owns the resource, Forbidden otherwise
+pub async fn authorize(pool: &MySqlPool, auth: &AuthUser, resource: Owned) -> Result<(), ApiError> {
+    let owner = match resource {
+        Owned::User(user_id) => user_id,
+        Owned::Food(food_id) => match get_food_owner(pool, food_id).await {
+            Ok(Some(owner)) => owner,
+            Ok(None) => return Err(ApiError::NotFound(format!("food not found"))),
+            Err(err) => return Err(ApiError::db("there was an error checking ownership", err))
+        }
+    };
+
+    if owner == auth.user_id {
+        Ok(())
+    }else{
+        Err(ApiError::Forbidden(format!("you are not allowed to modify this resource")))
+    }
+}
+
+pub async fn require_admin(pool: &MySqlPool, auth: &AuthUser) -> Result<(), ApiError> {
+    match is_admin(pool, auth.user_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::Forbidden(format!("admins only"))),
+        Err(err) => Err(ApiError::db("there was an error checking permissions", err))
+    }
+}
@@ -1,7 +1,15 @@
 
 use serde::Deserialize;
-use sqlx::{FromRow, MySqlPool};
-use crate::functions::{check_code, compare_email, hash_password};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use sqlx::{FromRow, MySql, MySqlPool, QueryBuilder, Transaction};
+use crate::auth::{generate_token, PASSWORD_RESET_TTL_MINUTES, PICKUP_CODE_MAX_ATTEMPTS, REFRESH_TOKEN_TTL_DAYS, VERIFY_CODE_ATTEMPT_WINDOW_MINUTES, VERIFY_CODE_MAX_ATTEMPTS, VERIFY_CODE_TTL_MINUTES};
+use crate::errors::ApiError;
+use crate::functions::{compare_email, hash_password};
+use crate::geo::{bounding_box, GeocodeOutcome};
+use crate::reservation::{Actor, ReservationPolicy, ReservationStatus, ReserverStanding, RESERVATION_HOLD_HOURS};
+use crate::taxonomy::{parse_list, Allergen, Category, DietaryTag};
+use crate::waitlist::WAITLIST_OFFER_MINUTES;
 use crate::handlers::MajesticRes;
 // use serde_with::{serde_as, base64::Base64};
 
@@ -10,10 +18,27 @@ pub struct FoodDetail {
     pub title: String,
     pub description: String,
     pub is_free: bool,
-    pub pickup_time: String,
+    pub pickup_start: DateTime<FixedOffset>,
+    pub pickup_end: DateTime<FixedOffset>,
+    pub best_before: Option<DateTime<FixedOffset>>,
+    // portions on offer, 1 when not given. on edit None keeps what is there
+    pub quantity: Option<i32>,
+    pub max_per_user: Option<i32>,
+    // reservations wait for the donor to accept them. on edit None keeps what is there
+    pub requires_approval: Option<bool>,
     pub pickup_address: String,
     pub user_id: i32,
-    pub image: String,
+    pub image_key: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    #[sqlx(skip)]
+    pub category: Option<Category>,
+    #[serde(default)]
+    #[sqlx(skip)]
+    pub dietary_tags: Vec<DietaryTag>,
+    // None means the donor didn't say, an empty list means none of the 14 are in it
+    #[sqlx(skip)]
+    pub allergens: Option<Vec<Allergen>>,
 }
 
 #[derive(Debug, FromRow, serde::Deserialize, serde::Serialize)]
@@ -21,10 +46,27 @@ pub struct FoodDetail2 {
     pub title: String,
     pub description: String,
     pub is_free: bool,
-    pub pickup_time: String,
+    pub pickup_start: DateTime<FixedOffset>,
+    pub pickup_end: DateTime<FixedOffset>,
+    pub best_before: Option<DateTime<FixedOffset>>,
+    // portions on offer, 1 when not given. on edit None keeps what is there
+    pub quantity: Option<i32>,
+    pub max_per_user: Option<i32>,
+    // reservations wait for the donor to accept them. on edit None keeps what is there
+    pub requires_approval: Option<bool>,
     pub pickup_address: String,
     pub food_id: i32,
-    pub image: String,
+    pub image_key: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    #[sqlx(skip)]
+    pub category: Option<Category>,
+    #[serde(default)]
+    #[sqlx(skip)]
+    pub dietary_tags: Vec<DietaryTag>,
+    // None means the donor didn't say, an empty list means none of the 14 are in it
+    #[sqlx(skip)]
+    pub allergens: Option<Vec<Allergen>>,
 }
 
 #[derive(Debug, FromRow, serde::Serialize)]
@@ -33,31 +75,92 @@ pub struct Food {
     pub title: Option<String>,
     pub description: Option<String>,
     pub is_free: Option<i8>,
-    pub pickup_time: Option<String>,
+    pub pickup_start: Option<DateTime<Utc>>,
+    pub pickup_end: Option<DateTime<Utc>>,
+    pub pickup_utc_offset: Option<String>,
     pub pickup_address: Option<String>,
     pub user_id: Option<i32>,
-    pub image: Option<String>,
-    pub status: Option<String>
+    pub image_url: Option<String>,
+    pub status: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub category: Option<String>,
+    pub quantity: Option<i32>,
+    pub quantity_remaining: Option<i32>,
+    pub max_per_user: Option<i32>,
+    pub requires_approval: Option<i8>,
+    pub best_before: Option<DateTime<Utc>>,
+    // negative once it has passed, until the scheduler delists the food
+    pub time_remaining_secs: Option<i64>
+}
+
+#[derive(serde::Serialize)]
+pub struct FoodProfile {
+    #[serde(flatten)]
+    pub food: Food,
+    pub dietary_tags: Vec<String>,
+    pub allergens_declared: bool,
+    pub allergens: Vec<String>,
+    pub waitlist_length: i64,
+    // only filled in for a signed in caller who is on the waitlist
+    pub waitlist_position: Option<i64>
+}
+
+#[derive(serde::Serialize)]
+pub struct NewFood {
+    pub id: u64,
+    pub geocoding: GeocodeOutcome
+}
+
+#[derive(serde::Serialize)]
+pub struct EditedFood {
+    #[serde(flatten)]
+    pub food: FoodDetail2,
+    pub geocoding: GeocodeOutcome
+}
+
+#[derive(Debug, FromRow, serde::Serialize)]
+pub struct NearbyFood {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub food: Food,
+    pub distance_km: f64
+}
+
+#[derive(serde::Deserialize)]
+pub struct NearbyQuery{
+    pub lat: f64,
+    pub lng: f64,
+    pub radius_km: Option<f64>,
+    pub limit: Option<i64>
 }
 
 #[derive(Debug, FromRow, serde::Serialize)]
 pub struct AllReserves{
+    pub reservation_id: i32,
     pub food_id: i32,
+    pub status: String,
     pub title: Option<String>,
     pub description: Option<String>,
     pub first_name: Option<String>,
-    pub image: Option<String>
+    pub image_url: Option<String>,
+    pub quantity: i32
 }
 
 #[derive(Debug, FromRow, serde::Serialize)]
 pub struct ActiveReserve{
+    pub reservation_id: i32,
     pub food_id: i32,
+    pub status: String,
     pub title: Option<String>,
     pub description: Option<String>,
     pub first_name: Option<String>,
-    pub image: Option<String>,
-    pub pickup_time: Option<String>,
-    pub pickup_address: Option<String>
+    pub image_url: Option<String>,
+    pub pickup_start: Option<DateTime<Utc>>,
+    pub pickup_end: Option<DateTime<Utc>>,
+    pub pickup_utc_offset: Option<String>,
+    pub pickup_address: Option<String>,
+    pub quantity: i32
 }
 
 #[derive(Debug, FromRow, serde::Serialize)]
@@ -84,14 +187,15 @@ pub struct LoginDetail {
 
 #[derive(serde::Serialize, Deserialize)]
 pub struct UserDetails{
-    id: Option<i32>,
+    pub id: Option<i32>,
     email: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
     num_of_food_added: Option<i32>,
     num_of_food_taken: Option<i32>,
-    profile_image: Option<String>,
+    profile_image_url: Option<String>,
     email_verified: Option<i8>,
+    #[serde(skip_serializing)]
     pub password_hash: String
 }
 
@@ -103,7 +207,7 @@ pub struct GetUserDetails{
     last_name: Option<String>,
     num_of_food_added: Option<i32>,
     num_of_food_taken: Option<i32>,
-    profile_image: Option<String>,
+    profile_image_url: Option<String>,
     email_verified: Option<i8>,
 }
 
@@ -111,19 +215,34 @@ pub struct GetUserDetails{
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct PictureDetails{
     pub user_id: i32, 
-    pub profile_image: Option<String>
+    pub profile_image_key: Option<String>
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct PicturePayload{
-    pub profile_image: Option<String>
+    pub profile_image_key: Option<String>
+}
+
+#[derive(serde::Serialize)]
+pub struct UploadedMedia{
+    pub key: String,
+    pub url: String,
+    pub thumbnail_url: String,
+    pub content_type: String,
+    pub byte_size: i64
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct UserCodeDetails{
-    pub user_code: String, 
-    pub user_id: i32,
-    pub user_email: String
+    pub user_code: String
+}
+
+pub enum CodeCheck{
+    Verified,
+    Wrong { attempts_left: i32 },
+    Locked,
+    Expired,
+    Missing
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -134,19 +253,236 @@ pub struct EditUserDetails{
     pub email: String,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, FromRow, Debug,)]
-pub struct ReserveDetails{
-    pub user_id: i32,
-    pub food_id: i32
+#[derive(serde::Deserialize)]
+pub struct ReservePayload{
+    pub food_id: i32,
+    pub quantity: Option<i32>
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct ReservationDetails{
     id: i32,
     user_id: i32,
-    food_id: i32,
+    pub food_id: i32,
+    quantity: i32,
     reserved_at: Option<String>,
-    status: Option<String>
+    pub status: Option<String>,
+    cancelled_by: Option<String>,
+    cancel_reason: Option<String>
+}
+
+#[derive(serde::Deserialize)]
+pub struct CancelPayload{
+    pub reason: Option<String>
+}
+
+pub struct ReservationParties{
+    pub receiver_id: i32,
+    pub receiver_email: String,
+    pub donor_email: String,
+    pub food_title: Option<String>
+}
+
+#[derive(Debug, FromRow, serde::Serialize)]
+pub struct ReservationRequest{
+    pub reservation_id: i32,
+    pub user_id: i32,
+    pub first_name: Option<String>,
+    pub quantity: i32,
+    pub requested_at: Option<String>,
+    pub no_show_count: i32
+}
+
+pub struct PickupSecret{
+    pub receiver_id: i32,
+    pub donor_id: Option<i32>,
+    pub status: String,
+    pub pickup_nonce: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>
+}
+
+#[derive(serde::Serialize)]
+pub struct PickupCode{
+    pub reservation_id: i32,
+    pub code: String,
+    pub qr_payload: String,
+    pub expires_at: Option<DateTime<Utc>>
+}
+
+#[derive(serde::Deserialize)]
+pub struct PickupPayload{
+    pub code: String
+}
+
+// what the live feed sends about a food, enough for a client to patch its list without refetching
+#[derive(Clone, Debug, FromRow, serde::Serialize)]
+pub struct FeedFood{
+    pub id: Option<i32>,
+    pub title: Option<String>,
+    pub user_id: Option<i32>,
+    pub status: Option<String>,
+    pub category: Option<String>,
+    pub quantity_remaining: Option<i32>,
+    pub pickup_end: Option<DateTime<Utc>>,
+    pub image_url: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>
+}
+
+#[derive(serde::Deserialize)]
+pub struct FeedQuery{
+    // lat and lng together narrow the feed to an area around them
+    pub lat: Option<f64>,
+    pub lng: Option<f64>,
+    pub radius_km: Option<f64>,
+    // comma separated, any of them matches
+    pub category: Option<String>
+}
+
+pub struct ReservationMembers{
+    pub receiver_id: i32,
+    pub donor_id: Option<i32>,
+    pub status: String
+}
+
+#[derive(Debug, FromRow, serde::Serialize)]
+pub struct Message{
+    pub id: i32,
+    pub sender_id: i32,
+    pub body: String,
+    pub sent_at: Option<String>,
+    pub read_at: Option<String>
+}
+
+#[derive(serde::Deserialize)]
+pub struct MessagePayload{
+    pub body: String
+}
+
+#[derive(serde::Serialize)]
+pub struct MessageThread{
+    pub reservation_id: i32,
+    pub closed: bool,
+    #[serde(flatten)]
+    pub messages: Page<Message>
+}
+
+#[derive(serde::Deserialize)]
+pub struct WaitlistPayload{
+    pub quantity: Option<i32>
+}
+
+#[derive(serde::Serialize)]
+pub struct WaitlistSpot{
+    pub food_id: i32,
+    pub position: i64
+}
+
+pub struct WaitlistOffer{
+    pub email: String,
+    pub food_title: Option<String>
+}
+
+#[derive(Debug, FromRow)]
+pub struct SpoiledFood{
+    pub id: i32,
+    pub title: Option<String>,
+    pub status: Option<String>,
+    pub donor_email: String
+}
+
+#[derive(serde::Serialize)]
+pub struct SessionTokens{
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_in: u64
+}
+
+#[derive(serde::Serialize)]
+pub struct LoginResponse{
+    pub user: UserDetails,
+    #[serde(flatten)]
+    pub tokens: SessionTokens
+}
+
+#[derive(serde::Deserialize)]
+pub struct RefreshPayload{
+    pub refresh_token: String
+}
+
+#[derive(serde::Deserialize)]
+pub struct ForgotPasswordPayload{
+    pub email: String
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetPasswordPayload{
+    pub token: String,
+    pub new_password: String
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// One slice of a list endpoint, pass `next_cursor` back as `cursor` to get the next one.
+#[derive(serde::Serialize)]
+pub struct Page<T>{
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>
+}
+
+impl<T> Page<T> {
+    // rows are fetched with one extra item so we know whether another page exists
+    fn from_rows(mut rows: Vec<T>, limit: i64, cursor_of: impl Fn(&T) -> String) -> Self {
+        let has_more = rows.len() as i64 > limit;
+        rows.truncate(limit as usize);
+        let next_cursor = if has_more { rows.last().map(|row| encode_cursor(&cursor_of(row))) } else { None };
+        Page { items: rows, next_cursor }
+    }
+}
+
+fn page_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+fn encode_cursor(cursor: &str) -> String {
+    URL_SAFE_NO_PAD.encode(cursor)
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+}
+
+#[derive(serde::Deserialize)]
+pub struct PageQuery{
+    pub cursor: Option<String>,
+    pub limit: Option<i64>
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FoodSort{
+    #[default]
+    Newest,
+    Pickup
+}
+
+#[derive(serde::Deserialize)]
+pub struct FoodQuery{
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    pub status: Option<String>,
+    pub is_free: Option<bool>,
+    pub donor_id: Option<i32>,
+    pub pickup_from: Option<DateTime<Utc>>,
+    pub pickup_to: Option<DateTime<Utc>>,
+    pub category: Option<Category>,
+    // comma separated, every tag has to be on the food
+    pub dietary: Option<String>,
+    // comma separated, only foods declared free of all of them
+    pub exclude_allergens: Option<String>,
+    pub sort: Option<FoodSort>
 }
 
 #[derive(serde::Serialize)] pub struct ApiResponse<T>{
@@ -155,24 +491,72 @@ pub struct ReservationDetails{
     pub data: Option<T>
 }
 
+// tags and allergens are replaced as a whole, whatever the food had before goes
+async fn replace_food_labels(tx: &mut Transaction<'_, MySql>, food_id: u64, dietary_tags: &[DietaryTag], allergens: &Option<Vec<Allergen>>) -> Result<(), sqlx::Error>{
+    sqlx::query!("DELETE FROM food_dietary_tags WHERE food_id = ?", food_id).execute(&mut **tx).await?;
+    sqlx::query!("DELETE FROM food_allergens WHERE food_id = ?", food_id).execute(&mut **tx).await?;
+
+    for tag in dietary_tags {
+        sqlx::query!(
+            r#"
+                INSERT IGNORE INTO food_dietary_tags (food_id, tag) VALUES (?, ?)
+            "#,
+            food_id,
+            tag.as_str()
+        ).execute(&mut **tx).await?;
+    }
+    for allergen in allergens.iter().flatten() {
+        sqlx::query!(
+            r#"
+                INSERT IGNORE INTO food_allergens (food_id, allergen) VALUES (?, ?)
+            "#,
+            food_id,
+            allergen.as_str()
+        ).execute(&mut **tx).await?;
+    }
+
+    sqlx::query!(
+        r#"
+            UPDATE foods SET allergens_declared = ? WHERE id = ?
+        "#,
+        allergens.is_some(),
+        food_id
+    ).execute(&mut **tx).await?;
+    Ok(())
+}
+
 pub async fn insert_food(pool: &MySqlPool, food: &FoodDetail) -> Result<u64, sqlx::Error>{
+    let mut tx = pool.begin().await?;
     let result = sqlx::query!(
         r#"
-            INSERT INTO foods (title, description, is_free, pickup_time, user_id, image, pickup_address)
-            VALUES(?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO foods (title, description, is_free, pickup_start, pickup_end, pickup_utc_offset, user_id, image_key, pickup_address, latitude, longitude, category, best_before,
+            quantity, quantity_remaining, max_per_user, requires_approval)
+            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#,
         food.title,
         food.description,
         food.is_free,
-        food.pickup_time,
+        food.pickup_start.naive_utc(),
+        food.pickup_end.naive_utc(),
+        food.pickup_start.offset().to_string(),
         food.user_id,
-        food.image,
-        food.pickup_address
+        food.image_key,
+        food.pickup_address,
+        food.latitude,
+        food.longitude,
+        food.category.map(|c| c.as_str()),
+        food.best_before.map(|b| b.naive_utc()),
+        food.quantity.unwrap_or(1),
+        food.quantity.unwrap_or(1),
+        food.max_per_user,
+        food.requires_approval.unwrap_or(false)
     )
-    .execute(pool)
+    .execute(&mut *tx)
     .await?;
 
     let last_id = result.last_insert_id();
+    replace_food_labels(&mut tx, last_id, &food.dietary_tags, &food.allergens).await?;
+    tx.commit().await?;
     Ok(last_id)
 }
 
@@ -189,17 +573,93 @@ pub async fn increment_user_food_count(pool: &MySqlPool, user_id: i32) -> Result
     Ok(())
 }
 
-pub async fn get_all_food(pool: &MySqlPool) -> Result<Vec<Food>, sqlx::Error> {
-    let food = sqlx::query_as!(
-        Food,
-        r#"
-            SELECT id, title, description, is_free, pickup_time, user_id, TO_BASE64(image) as image, pickup_address, status FROM foods
-        "#
-    )
-    .fetch_all(pool)
-    .await?;
+// rows the migration couldn't date sort after everything else
+fn no_pickup_start() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(9999, 12, 31).and_then(|d| d.and_hms_opt(23, 59, 59)).expect("valid date")
+}
 
-    Ok(food)
+pub async fn get_all_food(pool: &MySqlPool, params: &FoodQuery, default_status: Option<&str>) -> Result<Page<Food>, ApiError> {
+    let limit = page_limit(params.limit);
+    let sort = params.sort.unwrap_or_default();
+
+    let mut query = QueryBuilder::<MySql>::new(
+        "SELECT id, title, description, is_free, pickup_start, pickup_end, pickup_utc_offset, user_id, (SELECT CONCAT('/media/', COALESCE(m.thumbnail_key, m.media_key)) FROM media m WHERE m.media_key = foods.image_key) as image_url, pickup_address, status, latitude, longitude, category, quantity, quantity_remaining, max_per_user, requires_approval, best_before, TIMESTAMPDIFF(SECOND, NOW(), best_before) AS time_remaining_secs FROM foods WHERE 1 = 1"
+    );
+    let status = params.status.as_deref().or(default_status);
+    match status {
+        Some("any") | None => {}
+        Some(status) => { query.push(" AND status = ").push_bind(status.to_string()); }
+    }
+    // food whose pickup window has closed stays active until its reservations run out, but nobody can come for it anymore
+    if status == Some("active") && params.pickup_from.is_none() {
+        query.push(" AND (pickup_end IS NULL OR pickup_end > NOW())");
+    }
+    if let Some(is_free) = params.is_free {
+        query.push(" AND is_free = ").push_bind(is_free);
+    }
+    if let Some(donor_id) = params.donor_id {
+        query.push(" AND user_id = ").push_bind(donor_id);
+    }
+    if let Some(category) = params.category {
+        query.push(" AND category = ").push_bind(category.as_str());
+    }
+    if let Some(dietary) = &params.dietary {
+        let tags = parse_list(dietary, DietaryTag::parse).map_err(|err| ApiError::BadRequest(format!("dietary: {}", err)))?;
+        for tag in tags {
+            query.push(" AND EXISTS (SELECT 1 FROM food_dietary_tags t WHERE t.food_id = foods.id AND t.tag = ").push_bind(tag.as_str()).push(")");
+        }
+    }
+    // a food that never declared its allergens can't be promised free of any
+    if let Some(exclude) = &params.exclude_allergens {
+        let allergens = parse_list(exclude, Allergen::parse).map_err(|err| ApiError::BadRequest(format!("exclude_allergens: {}", err)))?;
+        if !allergens.is_empty() {
+            query.push(" AND allergens_declared = 1 AND NOT EXISTS (SELECT 1 FROM food_allergens a WHERE a.food_id = foods.id AND a.allergen IN (");
+            let mut list = query.separated(", ");
+            for allergen in allergens {
+                list.push_bind(allergen.as_str());
+            }
+            query.push("))");
+        }
+    }
+    // a window matches when it overlaps [pickup_from, pickup_to]
+    if let Some(from) = params.pickup_from {
+        query.push(" AND pickup_end >= ").push_bind(from.naive_utc());
+    }
+    if let Some(to) = params.pickup_to {
+        query.push(" AND pickup_start <= ").push_bind(to.naive_utc());
+    }
+
+    if let Some(cursor) = &params.cursor {
+        let cursor = decode_cursor(cursor).ok_or_else(|| ApiError::BadRequest(format!("invalid cursor")))?;
+        match sort {
+            FoodSort::Newest => {
+                let last_id: i32 = cursor.parse().map_err(|_| ApiError::BadRequest(format!("invalid cursor")))?;
+                query.push(" AND id < ").push_bind(last_id);
+            }
+            FoodSort::Pickup => {
+                let (last_pickup, last_id) = cursor.rsplit_once('|').ok_or_else(|| ApiError::BadRequest(format!("invalid cursor")))?;
+                let last_pickup: i64 = last_pickup.parse().map_err(|_| ApiError::BadRequest(format!("invalid cursor")))?;
+                let last_pickup = DateTime::from_timestamp(last_pickup, 0).ok_or_else(|| ApiError::BadRequest(format!("invalid cursor")))?.naive_utc();
+                let last_id: i32 = last_id.parse().map_err(|_| ApiError::BadRequest(format!("invalid cursor")))?;
+                query.push(" AND (COALESCE(pickup_start, ").push_bind(no_pickup_start()).push(") > ").push_bind(last_pickup)
+                    .push(" OR (COALESCE(pickup_start, ").push_bind(no_pickup_start()).push(") = ").push_bind(last_pickup)
+                    .push(" AND id > ").push_bind(last_id).push("))");
+            }
+        }
+    }
+
+    match sort {
+        FoodSort::Newest => query.push(" ORDER BY id DESC"),
+        FoodSort::Pickup => query.push(" ORDER BY pickup_start IS NULL, pickup_start ASC, id ASC"),
+    };
+    query.push(" LIMIT ").push_bind(limit + 1);
+
+    let food = query.build_query_as::<Food>().fetch_all(pool).await?;
+
+    Ok(Page::from_rows(food, limit, |f| match sort {
+        FoodSort::Newest => f.id.unwrap_or_default().to_string(),
+        FoodSort::Pickup => format!("{}|{}", f.pickup_start.map(|s| s.naive_utc()).unwrap_or_else(no_pickup_start).and_utc().timestamp(), f.id.unwrap_or_default()),
+    }))
 }
 
 pub async fn check_if_email_exists(pool: &MySqlPool, email: String) -> Result<bool, sqlx::Error> {
@@ -239,7 +699,7 @@ pub async fn login_user(pool: &MySqlPool, login_details: &LoginDetail) -> Result
         UserDetails,
         r#"
             SELECT id, email, last_name, first_name, num_of_food_added,
-            num_of_food_taken, TO_BASE64(profile_image) as profile_image, password_hash, email_verified FROM users WHERE email = ? AND is_active = 1
+            num_of_food_taken, CONCAT('/media/', profile_image_key) as profile_image_url, password_hash, email_verified FROM users WHERE email = ? AND is_active = 1
         "#,
         login_details.email
     )
@@ -265,48 +725,15 @@ pub async fn delete_food(pool: &MySqlPool, id: i32) -> Result<(), sqlx::Error>{
 pub async fn edit_profile_picture(pool: &MySqlPool, picture: &PictureDetails) -> Result<(), sqlx::Error>{
     sqlx::query!(
         r#"
-            UPDATE users SET profile_image = ? WHERE id = ? AND is_active = 1
+            UPDATE users SET profile_image_key = ? WHERE id = ? AND is_active = 1
         "#,
-        picture.profile_image,
+        picture.profile_image_key,
         picture.user_id
     ).execute(pool).await?;
 
     Ok(())
 }
 
-pub async fn verify_user_code(pool: &MySqlPool, user_code: &UserCodeDetails) -> Result<bool, sqlx::Error> {
-    let code_db = sqlx::query!(
-        r#"
-            SELECT code_pass FROM users WHERE id = ?
-        "#,
-        user_code.user_id
-    ).fetch_one(pool).await?;
-
-    Ok(check_code(&user_code.user_code, code_db.code_pass.expect("REASON")))
-}
-
-pub async fn update_verified(pool: &MySqlPool, user_email: &String) -> Result<(), sqlx::Error>{
-    sqlx::query!(
-        r#"
-            UPDATE users SET email_verified = 1 WHERE email = ? AND is_active = 1
-        "#,
-        user_email
-    ).execute(pool).await?;
-
-    Ok(())
-}
-
-pub async fn delete_verification_code(pool: &MySqlPool,  user_email: &String) -> Result<(), sqlx::Error>{
-    sqlx::query!(
-        r#"
-            UPDATE users SET code_pass = "" WHERE email = ?
-        "#,
-        user_email
-    ).execute(pool).await?;
-
-    Ok(())
-}
-
 pub async fn get_user_email(pool: &MySqlPool, id: &i32) ->Result<String, sqlx::Error>{
     let record = sqlx::query!(
         r#"
@@ -318,18 +745,6 @@ pub async fn get_user_email(pool: &MySqlPool, id: &i32) ->Result<String, sqlx::E
     Ok(record.email)
 }
 
-pub async fn add_user_code(pool: &MySqlPool, code: String, user_id: i32) -> Result<(), sqlx::Error>{
-    sqlx::query!(
-        r#"
-            UPDATE users SET code_pass = ? WHERE id = ?
-        "#, 
-        code,
-        user_id
-    ).execute( pool).await?;
-
-    Ok(())
-}
-
 //edit_user_profile (check if the email was changed, if yes changed the verified email to false)
 pub async fn edit_user_profile(pool: &MySqlPool, edit_user_details: &EditUserDetails) -> Result<(), sqlx::Error>{
     sqlx::query!(
@@ -367,119 +782,121 @@ pub async fn delete_user_account(pool: &MySqlPool, user_id: i32, user_mail: &Str
     Ok(())
 }
 
-pub async fn make_reserve(pool: &MySqlPool, reserve_details: ReserveDetails) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"
-            INSERT INTO reservations (user_id, food_id) VALUES (?, ?)
-        "#,
-        reserve_details.user_id,
-        reserve_details.food_id
-    ).execute(pool).await?;
-
-    Ok(())
-}
-
-pub async fn mark_user_reserve(pool: &MySqlPool, user_id: i32) -> Result<u64, sqlx::Error>{
-    let result = sqlx::query!(
-        r#"
-            UPDATE users SET has_reserve = 1 WHERE id = ?
-        "#,
-        user_id
-    ).execute(pool).await?;
-    let id = result.last_insert_id();
-    Ok(id)
-}
-
-pub async fn get_reservation_details(pool: &MySqlPool, id: u64) -> Result<ReservationDetails, sqlx::Error>{
-    let reservation_details = sqlx::query_as!(
-        ReservationDetails,
-        r#"
-            SELECT id, user_id, food_id, DATE_FORMAT(reserved_at, '%Y-%m-%d %H:%i:%s') as reserved_at, status FROM reservations WHERE id = ?
-        "#,
-        id
-    ).fetch_one(pool).await?;
-
-    Ok(reservation_details)
-}
-
-pub async fn check_if_user_has_reserve(pool: &MySqlPool, user_id: i32) -> Result<bool, sqlx::Error>{
-   let result = sqlx::query_scalar!(
-    r#"
-        SELECT has_reserve FROM users WHERE id = ? AND has_reserve = 1
-    "#,
-    user_id
-   ).fetch_optional(pool).await?;
-
-   Ok(result.is_some())
-}
+pub async fn get_user_reservations(pool: &MySqlPool, user_id: i32, params: &PageQuery) -> Result<Page<AllReserves>, ApiError>{
+    let limit = page_limit(params.limit);
+    let before_id = match &params.cursor {
+        Some(cursor) => decode_cursor(cursor)
+            .and_then(|c| c.parse::<i32>().ok())
+            .ok_or_else(|| ApiError::BadRequest(format!("invalid cursor")))?,
+        None => i32::MAX
+    };
 
-pub async fn get_user_reservations(pool: &MySqlPool, user_id: i32) -> Result<Vec<AllReserves>, sqlx::Error>{
     let all_reserve = sqlx::query_as!(
         AllReserves, 
         r#"
-            SELECT r.food_id, f.title, f.description, u.first_name, TO_BASE64(f.image) as image FROM reservations r
+            SELECT r.id AS reservation_id, r.food_id, r.status, f.title, f.description, u.first_name, r.quantity,
+            (SELECT CONCAT('/media/', COALESCE(m.thumbnail_key, m.media_key)) FROM media m WHERE m.media_key = f.image_key) as image_url
+            FROM reservations r
             INNER JOIN users u on u.id = r.user_id INNER JOIN foods f on
-            f.id = r.food_id WHERE r.user_id = ?
+            f.id = r.food_id WHERE r.user_id = ? AND r.id < ?
+            ORDER BY r.id DESC LIMIT ?
         "#,
-        user_id
+        user_id,
+        before_id,
+        limit + 1
     ).fetch_all(pool).await?;
 
-    Ok(all_reserve)
+    Ok(Page::from_rows(all_reserve, limit, |r| r.reservation_id.to_string()))
 }
 
 // get_active_reserve
-pub async fn get_active_reserve(pool: &MySqlPool, user_id: i32) ->Result<ActiveReserve, sqlx::Error>{
+pub async fn get_active_reserve(pool: &MySqlPool, user_id: i32) ->Result<Vec<ActiveReserve>, sqlx::Error>{
     let active_reserve = sqlx::query_as!(
         ActiveReserve,
         r#"
-            SELECT food_id, title, description, first_name, TO_BASE64(image) as image, 
-            pickup_time, pickup_address FROM reservations r
+            SELECT r.id AS reservation_id, food_id, r.status, title, description, first_name,
+            (SELECT CONCAT('/media/', COALESCE(m.thumbnail_key, m.media_key)) FROM media m WHERE m.media_key = f.image_key) as image_url,
+            pickup_start AS "pickup_start: DateTime<Utc>", pickup_end AS "pickup_end: DateTime<Utc>", pickup_utc_offset, pickup_address, r.quantity FROM reservations r
             INNER JOIN users u on u.id = r.user_id 
             INNER JOIN foods f on f.id = r.food_id
-            WHERE r.user_id = ? AND u.has_reserve = 1
-            AND r.status = 'active'
+            WHERE r.user_id = ? AND r.status IN ('pending', 'requested', 'confirmed')
+            ORDER BY r.expires_at
         "#,
         user_id
-    ).fetch_one(pool).await?;
+    ).fetch_all(pool).await?;
 
     Ok(active_reserve)
 }
 
 //get_all_donations
-pub async fn get_all_donations(pool: &MySqlPool, user_id: i32) ->Result<Vec<Food>, sqlx::Error>{
-    let all_donations = sqlx::query_as!(
-        Food,
-        r#"
-            SELECT id, title, description, is_free, pickup_time, user_id, TO_BASE64(image) as image, pickup_address, status
-            FROM foods
-            WHERE user_id = ?
-        "#,
-        user_id
-    ).fetch_all(pool)
-    .await?;
-
-    Ok(all_donations)
+pub async fn get_all_donations(pool: &MySqlPool, user_id: i32, params: FoodQuery) ->Result<Page<Food>, ApiError>{
+    let params = FoodQuery { donor_id: Some(user_id), ..params };
+    get_all_food(pool, &params, None).await
 }
 
 //update_donation
-pub async fn update_donation(pool: &MySqlPool, food: &FoodDetail2) -> Result<(), sqlx::Error> {
+pub async fn update_donation(pool: &MySqlPool, food: &FoodDetail2) -> Result<(), ApiError> {
+    let mut tx = pool.begin().await?;
+
+    // portions already claimed stay claimed, the new total has to leave room for them
+    if let Some(quantity) = food.quantity {
+        let current = sqlx::query!(
+            r#"
+                SELECT quantity, quantity_remaining FROM foods WHERE id = ? FOR UPDATE
+            "#,
+            food.food_id
+        ).fetch_one(&mut *tx).await?;
+        let claimed = current.quantity - current.quantity_remaining;
+        if quantity < claimed {
+            return Err(ApiError::Conflict(format!("{} portions are already claimed", claimed)));
+        }
+
+        sqlx::query!(
+            r#"
+                UPDATE foods SET quantity = ?, quantity_remaining = ?,
+                status = CASE
+                    WHEN status = 'active' AND quantity_remaining = 0 THEN 'reserved'
+                    WHEN status = 'reserved' AND quantity_remaining > 0 THEN 'active'
+                    ELSE status
+                END
+                WHERE id = ?
+            "#,
+            quantity,
+            quantity - claimed,
+            food.food_id
+        ).execute(&mut *tx).await?;
+    }
+
+    // no coordinates means none were sent and the address couldn't be geocoded, the old ones are still a better guess than none
     sqlx::query!(
         r#"
             UPDATE foods 
-            SET title = ?, description = ?, is_free = ?, pickup_time = ?, pickup_address = ?, image = ?
+            SET title = ?, description = ?, is_free = ?, pickup_start = ?, pickup_end = ?, pickup_utc_offset = ?,
+            pickup_address = ?, image_key = ?, latitude = COALESCE(?, latitude), longitude = COALESCE(?, longitude), category = ?, best_before = ?, max_per_user = COALESCE(?, max_per_user),
+            requires_approval = COALESCE(?, requires_approval)
             WHERE id = ?
         "#,
         food.title,
         food.description,
         food.is_free,
-        food.pickup_time,
+        food.pickup_start.naive_utc(),
+        food.pickup_end.naive_utc(),
+        food.pickup_start.offset().to_string(),
         food.pickup_address,
-        food.image,
+        food.image_key,
+        food.latitude,
+        food.longitude,
+        food.category.map(|c| c.as_str()),
+        food.best_before.map(|b| b.naive_utc()),
+        food.max_per_user,
+        food.requires_approval,
         food.food_id
     )
-    .execute(pool)
+    .execute(&mut *tx)
     .await?;
 
+    replace_food_labels(&mut tx, food.food_id as u64, &food.dietary_tags, &food.allergens).await?;
+    tx.commit().await?;
     Ok(())
 }
 
@@ -488,7 +905,9 @@ pub async fn get_active_donation(pool: &MySqlPool, user_id: i32) -> Result<Vec<F
     let active_donation = sqlx::query_as!(
         Food,
         r#"
-            SELECT id, title, description, is_free, pickup_time, user_id, TO_BASE64(image) as image, pickup_address, status 
+            SELECT id, title, description, is_free, pickup_start AS "pickup_start: DateTime<Utc>", pickup_end AS "pickup_end: DateTime<Utc>", pickup_utc_offset, user_id,
+            (SELECT CONCAT('/media/', COALESCE(m.thumbnail_key, m.media_key)) FROM media m WHERE m.media_key = foods.image_key) as image_url, pickup_address, status,
+            latitude, longitude, category, quantity, quantity_remaining, max_per_user, requires_approval, best_before AS "best_before: DateTime<Utc>", TIMESTAMPDIFF(SECOND, NOW(), best_before) AS time_remaining_secs
             from foods WHERE user_id = ? and status = 'active'
         "#,
         user_id
@@ -498,33 +917,12 @@ pub async fn get_active_donation(pool: &MySqlPool, user_id: i32) -> Result<Vec<F
     Ok(active_donation)
 }
 
-pub async fn edit_reservation(pool: &MySqlPool, reserve_details: ReserveDetails) -> Result<(), sqlx::Error>{
-    sqlx::query!(
-        r#"
-            UPDATE reservations SET status = 'cancelled'
-            WHERE user_id = ? OR food_id = ?
-        "#,
-        reserve_details.user_id,
-        reserve_details.food_id
-    ).execute(pool).await?;
-
-    sqlx::query!(
-        r#"
-            UPDATE users SET has_reserve = 0
-            WHERE id = ?
-        "#,
-        reserve_details.user_id
-    ).execute(pool).await?;
-
-    Ok(())
-}
-
 pub async fn get_user_profile(pool: &MySqlPool, user_id: i32) -> Result<GetUserDetails, sqlx::Error>{
     let user_details = sqlx::query_as!(
         GetUserDetails,
         r#"
             SELECT id, email, first_name, last_name, num_of_food_added,
-            num_of_food_taken, TO_BASE64(profile_image) AS profile_image, email_verified FROM users
+            num_of_food_taken, CONCAT('/media/', profile_image_key) AS profile_image_url, email_verified FROM users
             WHERE id = ?
         "#,
         user_id
@@ -537,8 +935,9 @@ pub async fn get_food_detail(pool: &MySqlPool, food_id: i32) -> Result<Food, sql
     let food_details = sqlx::query_as!(
         Food,
         r#"
-            SELECT id, title, description, is_free, pickup_time, pickup_address, user_id,
-            TO_BASE64(image) AS image, status
+            SELECT id, title, description, is_free, pickup_start AS "pickup_start: DateTime<Utc>", pickup_end AS "pickup_end: DateTime<Utc>", pickup_utc_offset, pickup_address, user_id,
+            CONCAT('/media/', image_key) AS image_url, status, latitude, longitude, category,
+            quantity, quantity_remaining, max_per_user, requires_approval, best_before AS "best_before: DateTime<Utc>", TIMESTAMPDIFF(SECOND, NOW(), best_before) AS time_remaining_secs
             FROM foods WHERE id = ?
         "#,
         food_id
@@ -547,6 +946,56 @@ pub async fn get_food_detail(pool: &MySqlPool, food_id: i32) -> Result<Food, sql
     Ok(food_details)
 } 
 
+pub async fn get_feed_food(pool: &MySqlPool, food_id: i32) -> Result<Option<FeedFood>, sqlx::Error>{
+    let food = sqlx::query_as!(
+        FeedFood,
+        r#"
+            SELECT id, title, user_id, status, category, quantity_remaining, pickup_end AS "pickup_end: DateTime<Utc>",
+            (SELECT CONCAT('/media/', COALESCE(m.thumbnail_key, m.media_key)) FROM media m WHERE m.media_key = foods.image_key) as image_url,
+            latitude, longitude
+            FROM foods WHERE id = ?
+        "#,
+        food_id
+    ).fetch_optional(pool).await?;
+
+    Ok(food)
+}
+
+pub async fn get_food_profile(pool: &MySqlPool, food_id: i32, viewer: Option<i32>) -> Result<FoodProfile, sqlx::Error>{
+    let food = get_food_detail(pool, food_id).await?;
+    let dietary_tags = sqlx::query_scalar!(
+        r#"
+            SELECT tag FROM food_dietary_tags WHERE food_id = ? ORDER BY tag
+        "#,
+        food_id
+    ).fetch_all(pool).await?;
+    let allergens = sqlx::query_scalar!(
+        r#"
+            SELECT allergen FROM food_allergens WHERE food_id = ? ORDER BY allergen
+        "#,
+        food_id
+    ).fetch_all(pool).await?;
+    let allergens_declared = sqlx::query_scalar!(
+        r#"
+            SELECT allergens_declared AS "allergens_declared: bool" FROM foods WHERE id = ?
+        "#,
+        food_id
+    ).fetch_one(pool).await?;
+
+    let waitlist_length = sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*) AS "length!: i64" FROM food_waitlist WHERE food_id = ?
+        "#,
+        food_id
+    ).fetch_one(pool).await?;
+    let waitlist_position = match viewer {
+        Some(user_id) => waitlist_position(pool, food_id, user_id).await?,
+        None => None
+    };
+
+    Ok(FoodProfile { food, dietary_tags, allergens_declared, allergens, waitlist_length, waitlist_position })
+}
+
 pub async fn get_email(pool: &MySqlPool, user_id: &i32, inputted_email: &str) ->Result<bool, sqlx::Error>{
     let user_email = sqlx::query_as!(
         MajesticRes,
@@ -557,4 +1006,1213 @@ pub async fn get_email(pool: &MySqlPool, user_id: &i32, inputted_email: &str) ->
     ).fetch_one(pool).await?;
 
     Ok(compare_email(inputted_email, &user_email.user_email))
-}
\ No newline at end of file
+}
+
+pub async fn create_session(pool: &MySqlPool, user_id: i32, refresh_token_hash: &str) -> Result<u64, sqlx::Error>{
+    let result = sqlx::query!(
+        r#"
+            INSERT INTO sessions (user_id, refresh_token_hash, expires_at)
+            VALUES (?, ?, DATE_ADD(NOW(), INTERVAL ? DAY))
+        "#,
+        user_id,
+        refresh_token_hash,
+        REFRESH_TOKEN_TTL_DAYS
+    ).execute(pool).await?;
+
+    Ok(result.last_insert_id())
+}
+
+pub async fn is_session_active(pool: &MySqlPool, session_id: u64, user_id: i32) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        r#"
+            SELECT s.id FROM sessions s INNER JOIN users u ON u.id = s.user_id
+            WHERE s.id = ? AND s.user_id = ? AND s.revoked_at IS NULL
+            AND s.expires_at > NOW() AND u.is_active = 1
+        "#,
+        session_id,
+        user_id
+    ).fetch_optional(pool).await?;
+
+    Ok(result.is_some())
+}
+
+// swaps the refresh token of a live session, returns (session_id, user_id).
+// presenting an already rotated token revokes the whole session since it means the token leaked
+pub async fn rotate_session(pool: &MySqlPool, old_hash: &str, new_hash: &str) -> Result<Option<(u64, i32)>, sqlx::Error>{
+    let mut tx = pool.begin().await?;
+
+    let session = sqlx::query!(
+        r#"
+            SELECT id, user_id FROM sessions
+            WHERE refresh_token_hash = ? AND revoked_at IS NULL AND expires_at > NOW()
+            FOR UPDATE
+        "#,
+        old_hash
+    ).fetch_optional(&mut *tx).await?;
+
+    let session = match session {
+        Some(session) => session,
+        None => {
+            sqlx::query!(
+                r#"
+                    UPDATE sessions SET revoked_at = NOW()
+                    WHERE previous_token_hash = ? AND revoked_at IS NULL
+                "#,
+                old_hash
+            ).execute(&mut *tx).await?;
+            tx.commit().await?;
+            return Ok(None);
+        }
+    };
+
+    sqlx::query!(
+        r#"
+            UPDATE sessions SET previous_token_hash = refresh_token_hash, refresh_token_hash = ?,
+            expires_at = DATE_ADD(NOW(), INTERVAL ? DAY)
+            WHERE id = ?
+        "#,
+        new_hash,
+        REFRESH_TOKEN_TTL_DAYS,
+        session.id
+    ).execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(Some((session.id, session.user_id)))
+}
+
+pub async fn revoke_session(pool: &MySqlPool, session_id: u64) -> Result<(), sqlx::Error>{
+    sqlx::query!(
+        r#"
+            UPDATE sessions SET revoked_at = NOW() WHERE id = ? AND revoked_at IS NULL
+        "#,
+        session_id
+    ).execute(pool).await?;
+
+    Ok(())
+}
+
+pub async fn get_food_owner(pool: &MySqlPool, food_id: i32) -> Result<Option<i32>, sqlx::Error>{
+    let owner = sqlx::query_scalar!(
+        r#"
+            SELECT user_id AS "user_id?" FROM foods WHERE id = ?
+        "#,
+        food_id
+    ).fetch_optional(pool).await?;
+
+    Ok(owner.flatten())
+}
+
+pub async fn get_active_user_id(pool: &MySqlPool, email: &str) -> Result<Option<i32>, sqlx::Error>{
+    let user_id = sqlx::query_scalar!(
+        r#"
+            SELECT id FROM users WHERE email = ? AND is_active = 1
+        "#,
+        email
+    ).fetch_optional(pool).await?;
+
+    Ok(user_id)
+}
+
+pub async fn seconds_since_last_reset(pool: &MySqlPool, user_id: i32) -> Result<Option<i64>, sqlx::Error>{
+    let elapsed = sqlx::query_scalar!(
+        r#"
+            SELECT TIMESTAMPDIFF(SECOND, created_at, NOW()) AS "elapsed!: i64" FROM password_resets
+            WHERE user_id = ? ORDER BY id DESC LIMIT 1
+        "#,
+        user_id
+    ).fetch_optional(pool).await?;
+
+    Ok(elapsed)
+}
+
+// a user only ever has one usable reset token, asking again burns the older ones
+pub async fn create_password_reset(pool: &MySqlPool, user_id: i32, token_hash: &str) -> Result<(), sqlx::Error>{
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE password_resets SET used_at = NOW() WHERE user_id = ? AND used_at IS NULL
+        "#,
+        user_id
+    ).execute(&mut *tx).await?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO password_resets (user_id, token_hash, expires_at)
+            VALUES (?, ?, DATE_ADD(NOW(), INTERVAL ? MINUTE))
+        "#,
+        user_id,
+        token_hash,
+        PASSWORD_RESET_TTL_MINUTES
+    ).execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+// sets the new password and logs the user out everywhere, false when the token is unknown, used or expired
+pub async fn reset_password(pool: &MySqlPool, token_hash: &str, new_password: String) -> Result<bool, sqlx::Error>{
+    let mut tx = pool.begin().await?;
+
+    let reset = sqlx::query!(
+        r#"
+            SELECT id, user_id FROM password_resets
+            WHERE token_hash = ? AND used_at IS NULL AND expires_at > NOW()
+            FOR UPDATE
+        "#,
+        token_hash
+    ).fetch_optional(&mut *tx).await?;
+
+    let reset = match reset {
+        Some(reset) => reset,
+        None => return Ok(false)
+    };
+
+    sqlx::query!(
+        r#"
+            UPDATE password_resets SET used_at = NOW() WHERE id = ?
+        "#,
+        reset.id
+    ).execute(&mut *tx).await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE users SET password_hash = ? WHERE id = ? AND is_active = 1
+        "#,
+        hash_password(new_password),
+        reset.user_id
+    ).execute(&mut *tx).await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE sessions SET revoked_at = NOW() WHERE user_id = ? AND revoked_at IS NULL
+        "#,
+        reset.user_id
+    ).execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn seconds_since_last_code(pool: &MySqlPool, user_id: i32) -> Result<Option<i64>, sqlx::Error>{
+    let elapsed = sqlx::query_scalar!(
+        r#"
+            SELECT TIMESTAMPDIFF(SECOND, created_at, NOW()) AS "elapsed!: i64" FROM verification_codes
+            WHERE user_id = ? ORDER BY id DESC LIMIT 1
+        "#,
+        user_id
+    ).fetch_optional(pool).await?;
+
+    Ok(elapsed)
+}
+
+// a new code replaces whatever code the user still had pending
+pub async fn store_verification_code(pool: &MySqlPool, user_id: i32, code_hash: &str) -> Result<(), sqlx::Error>{
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE verification_codes SET consumed_at = NOW() WHERE user_id = ? AND consumed_at IS NULL
+        "#,
+        user_id
+    ).execute(&mut *tx).await?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO verification_codes (user_id, code_hash, expires_at)
+            VALUES (?, ?, DATE_ADD(NOW(), INTERVAL ? MINUTE))
+        "#,
+        user_id,
+        code_hash,
+        VERIFY_CODE_TTL_MINUTES
+    ).execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn check_verification_code(pool: &MySqlPool, user_id: i32, code_hash: &str) -> Result<CodeCheck, sqlx::Error>{
+    let mut tx = pool.begin().await?;
+
+    let pending = sqlx::query!(
+        r#"
+            SELECT id, code_hash, TIMESTAMPDIFF(SECOND, NOW(), expires_at) AS "seconds_left!: i64" FROM verification_codes
+            WHERE user_id = ? AND consumed_at IS NULL
+            ORDER BY id DESC LIMIT 1
+            FOR UPDATE
+        "#,
+        user_id
+    ).fetch_optional(&mut *tx).await?;
+
+    let pending = match pending {
+        Some(pending) => pending,
+        None => return Ok(CodeCheck::Missing)
+    };
+    if pending.seconds_left <= 0 {
+        return Ok(CodeCheck::Expired);
+    }
+    // wrong guesses on earlier codes count too, every attempt goes through the locked pending row
+    let recent_attempts = sqlx::query_scalar!(
+        r#"
+            SELECT CAST(COALESCE(SUM(attempts), 0) AS SIGNED) AS "attempts!: i64" FROM verification_codes
+            WHERE user_id = ? AND created_at > DATE_SUB(NOW(), INTERVAL ? MINUTE)
+        "#,
+        user_id,
+        VERIFY_CODE_ATTEMPT_WINDOW_MINUTES
+    ).fetch_one(&mut *tx).await? as i32;
+    if recent_attempts >= VERIFY_CODE_MAX_ATTEMPTS {
+        return Ok(CodeCheck::Locked);
+    }
+
+    if pending.code_hash != code_hash {
+        sqlx::query!(
+            r#"
+                UPDATE verification_codes SET attempts = attempts + 1 WHERE id = ?
+            "#,
+            pending.id
+        ).execute(&mut *tx).await?;
+        tx.commit().await?;
+
+        let attempts_left = VERIFY_CODE_MAX_ATTEMPTS - recent_attempts - 1;
+        if attempts_left <= 0 {
+            return Ok(CodeCheck::Locked);
+        }
+        return Ok(CodeCheck::Wrong { attempts_left });
+    }
+
+    sqlx::query!(
+        r#"
+            UPDATE verification_codes SET consumed_at = NOW() WHERE id = ?
+        "#,
+        pending.id
+    ).execute(&mut *tx).await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE users SET email_verified = 1 WHERE id = ? AND is_active = 1
+        "#,
+        user_id
+    ).execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(CodeCheck::Verified)
+}
+
+pub async fn is_admin(pool: &MySqlPool, user_id: i32) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        r#"
+            SELECT id FROM users WHERE id = ? AND is_admin = 1 AND is_active = 1
+        "#,
+        user_id
+    ).fetch_optional(pool).await?;
+
+    Ok(result.is_some())
+}
+
+// mysql applies SET left to right, the IF already sees the decremented count
+async fn claim_portions(tx: &mut Transaction<'_, MySql>, food_id: i32, quantity: i32) -> Result<(), sqlx::Error>{
+    sqlx::query!(
+        r#"
+            UPDATE foods SET quantity_remaining = quantity_remaining - ?,
+            status = IF(quantity_remaining = 0, 'reserved', 'active')
+            WHERE id = ?
+        "#,
+        quantity,
+        food_id
+    ).execute(&mut **tx).await?;
+    Ok(())
+}
+
+async fn release_portions(tx: &mut Transaction<'_, MySql>, food_id: i32, quantity: i32) -> Result<(), sqlx::Error>{
+    sqlx::query!(
+        r#"
+            UPDATE foods SET quantity_remaining = quantity_remaining + ?,
+            status = IF(status = 'reserved', 'active', status)
+            WHERE id = ?
+        "#,
+        quantity,
+        food_id
+    ).execute(&mut **tx).await?;
+    Ok(())
+}
+
+// everything happens under row locks on the food and the user so two people can't grab the same food
+pub async fn create_reservation(pool: &MySqlPool, policy: &ReservationPolicy, user_id: i32, food_id: i32, quantity: i32) -> Result<ReservationDetails, ApiError>{
+    let mut tx = pool.begin().await?;
+
+    let food = sqlx::query!(
+        r#"
+            SELECT user_id AS "user_id?", status AS "status?", TIMESTAMPDIFF(SECOND, NOW(), best_before) AS seconds_left,
+            CAST(COALESCE(pickup_end <= NOW(), 0) AS SIGNED) AS "window_closed!: i64",
+            quantity_remaining, max_per_user, CAST(requires_approval AS SIGNED) AS "requires_approval!: i64"
+            FROM foods WHERE id = ? FOR UPDATE
+        "#,
+        food_id
+    ).fetch_optional(&mut *tx).await?;
+
+    let food = food.ok_or_else(|| ApiError::NotFound(format!("food not found")))?;
+    if food.user_id == Some(user_id) {
+        return Err(ApiError::BadRequest(format!("you can't reserve your own donation")));
+    }
+    // an offer from the waitlist already claimed its portions, taking them up is all that's left
+    let offered = sqlx::query_scalar!(
+        r#"
+            SELECT quantity FROM food_waitlist
+            WHERE food_id = ? AND user_id = ? AND status = 'offered' AND offer_expires_at > NOW()
+        "#,
+        food_id,
+        user_id
+    ).fetch_optional(&mut *tx).await?;
+    match (food.status.as_deref(), offered) {
+        (Some("active"), None) => {}
+        (Some("active") | Some("reserved"), Some(_)) => {}
+        _ => return Err(ApiError::Conflict(format!("food is no longer available")))
+    }
+    // the line goes first, nobody gets past the people waiting for this food
+    if offered.is_none() {
+        let waiting = sqlx::query_scalar!(
+            r#"
+                SELECT COUNT(*) AS "waiting!: i64" FROM food_waitlist WHERE food_id = ? AND status = 'waiting' AND user_id <> ?
+            "#,
+            food_id,
+            user_id
+        ).fetch_one(&mut *tx).await?;
+        if waiting > 0 {
+            return Err(ApiError::Conflict(format!("people are waiting for this food, join the waitlist")));
+        }
+    }
+    // the scheduler may not have delisted it yet
+    if food.seconds_left.is_some_and(|s| s <= 0) {
+        return Err(ApiError::Conflict(format!("food is past its best-before date")));
+    }
+    if food.window_closed == 1 {
+        return Err(ApiError::Conflict(format!("pickup window has ended")));
+    }
+    match offered {
+        Some(offered) if quantity > offered => {
+            return Err(ApiError::Conflict(format!("you were offered {} portions", offered)));
+        }
+        Some(_) => {}
+        None if quantity > food.quantity_remaining => {
+            return Err(ApiError::Conflict(format!("only {} portions left", food.quantity_remaining)));
+        }
+        None => {}
+    }
+    if let Some(max_per_user) = food.max_per_user {
+        let claimed = sqlx::query_scalar!(
+            r#"
+                SELECT CAST(COALESCE(SUM(quantity), 0) AS SIGNED) AS "claimed!: i64" FROM reservations
+                WHERE user_id = ? AND food_id = ? AND status IN ('pending', 'requested', 'confirmed', 'picked_up')
+            "#,
+            user_id,
+            food_id
+        ).fetch_one(&mut *tx).await?;
+        if claimed + quantity as i64 > max_per_user as i64 {
+            return Err(ApiError::Conflict(format!("you can claim at most {} portions of this food", max_per_user)));
+        }
+    }
+
+    // the user row lock keeps two requests from the same user counting the same open reservations
+    let user = sqlx::query!(
+        r#"
+            SELECT CAST(COALESCE(email_verified, 0) AS SIGNED) AS "verified!: i64", CAST(is_organization AS SIGNED) AS "organization!: i64"
+            FROM users WHERE id = ? AND is_active = 1 FOR UPDATE
+        "#,
+        user_id
+    ).fetch_optional(&mut *tx).await?;
+    let user = user.ok_or_else(|| ApiError::NotFound(format!("user not found")))?;
+
+    let history = sqlx::query!(
+        r#"
+            SELECT
+                CAST(COALESCE(SUM(status IN ('pending', 'requested', 'confirmed')), 0) AS SIGNED) AS "active!: i64",
+                CAST(COALESCE(SUM(food_id = ? AND status IN ('pending', 'requested', 'confirmed')), 0) AS SIGNED) AS "open_on_food!: i64",
+                TIMESTAMPDIFF(SECOND, MAX(IF(status = 'no_show', status_changed_at, NULL)), NOW()) AS secs_since_no_show
+            FROM reservations WHERE user_id = ?
+        "#,
+        food_id,
+        user_id
+    ).fetch_one(&mut *tx).await?;
+    if history.open_on_food > 0 {
+        return Err(ApiError::Conflict(format!("you already have an open reservation for this food")));
+    }
+    let standing = ReserverStanding {
+        verified: user.verified == 1,
+        organization: user.organization == 1,
+        active: history.active,
+        secs_since_no_show: history.secs_since_no_show,
+    };
+    if let Err(reason) = policy.check(&standing) {
+        return Err(ApiError::Conflict(reason));
+    }
+
+    // with approval on, this is only a request: the donor picks who gets it, nothing is held yet
+    let needs_approval = food.requires_approval == 1;
+    let status = if needs_approval { ReservationStatus::Pending } else { ReservationStatus::Requested };
+    let reservation_id = sqlx::query!(
+        r#"
+            INSERT INTO reservations (user_id, food_id, quantity, status, expires_at)
+            SELECT ?, id, ?, ?, LEAST(DATE_ADD(NOW(), INTERVAL ? HOUR), COALESCE(pickup_end, DATE_ADD(NOW(), INTERVAL ? HOUR)))
+            FROM foods WHERE id = ?
+        "#,
+        user_id,
+        quantity,
+        status.as_str(),
+        RESERVATION_HOLD_HOURS,
+        RESERVATION_HOLD_HOURS,
+        food_id
+    ).execute(&mut *tx).await?.last_insert_id();
+
+    // whatever the offer claimed and the reservation doesn't hold goes back on the shelf
+    let held = if needs_approval { 0 } else { quantity };
+    match offered {
+        Some(offered) if offered > held => release_portions(&mut tx, food_id, offered - held).await?,
+        None if held > 0 => claim_portions(&mut tx, food_id, held).await?,
+        _ => {}
+    }
+
+    sqlx::query!(
+        r#"
+            DELETE FROM food_waitlist WHERE food_id = ? AND user_id = ?
+        "#,
+        food_id,
+        user_id
+    ).execute(&mut *tx).await?;
+
+    let reservation = sqlx::query_as!(
+        ReservationDetails,
+        r#"
+            SELECT id, user_id, food_id, quantity, DATE_FORMAT(reserved_at, '%Y-%m-%d %H:%i:%s') as reserved_at, status,
+            cancelled_by, cancel_reason FROM reservations WHERE id = ?
+        "#,
+        reservation_id
+    ).fetch_one(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(reservation)
+}
+
+// moves a reservation along its lifecycle and keeps the food and user counters in step.
+// caller is None when the scheduler is acting
+pub async fn transition_reservation(pool: &MySqlPool, reservation_id: i32, caller: Option<i32>, next: ReservationStatus, reason: Option<String>) -> Result<ReservationDetails, ApiError>{
+    let mut tx = pool.begin().await?;
+
+    let current = sqlx::query!(
+        r#"
+            SELECT r.user_id, r.food_id, r.quantity, r.status AS "status?", f.user_id AS "donor_id?"
+            FROM reservations r INNER JOIN foods f ON f.id = r.food_id
+            WHERE r.id = ?
+            FOR UPDATE
+        "#,
+        reservation_id
+    ).fetch_optional(&mut *tx).await?;
+
+    let current = current.ok_or_else(|| ApiError::NotFound(format!("reservation not found")))?;
+    let actor = match caller {
+        None => Actor::System,
+        Some(id) if current.donor_id == Some(id) => Actor::Donor,
+        Some(id) if current.user_id == id => Actor::Receiver,
+        Some(_) => return Err(ApiError::Forbidden(format!("you are not part of this reservation")))
+    };
+    if !next.allowed_for(actor) {
+        return Err(ApiError::Forbidden(format!("you can't mark this reservation as {}", next.as_str())));
+    }
+
+    let status = current.status.as_deref().and_then(ReservationStatus::parse);
+    let status = match status {
+        Some(status) if status.can_become(next) => status,
+        _ => return Err(ApiError::Conflict(format!(
+            "reservation can't go from {} to {}",
+            current.status.as_deref().unwrap_or("unknown"),
+            next.as_str()
+        )))
+    };
+
+    // an approved request takes its portions now, if they are still there
+    if next.holds_food() && !status.holds_food() {
+        let food = sqlx::query!(
+            r#"
+                SELECT status AS "status?", quantity_remaining FROM foods WHERE id = ? FOR UPDATE
+            "#,
+            current.food_id
+        ).fetch_one(&mut *tx).await?;
+        if food.status.as_deref() != Some("active") {
+            return Err(ApiError::Conflict(format!("food is no longer available")));
+        }
+        if current.quantity > food.quantity_remaining {
+            return Err(ApiError::Conflict(format!("only {} portions left", food.quantity_remaining)));
+        }
+        claim_portions(&mut tx, current.food_id, current.quantity).await?;
+    }
+
+    // confirming hands out the pickup nonce, any later step retires it so a code works once
+    let pickup_nonce = if next == ReservationStatus::Confirmed { Some(generate_token()) } else { None };
+    sqlx::query!(
+        r#"
+            UPDATE reservations SET status = ?, status_changed_at = NOW(), pickup_nonce = ? WHERE id = ?
+        "#,
+        next.as_str(),
+        pickup_nonce,
+        reservation_id
+    ).execute(&mut *tx).await?;
+
+    if matches!(next, ReservationStatus::Cancelled | ReservationStatus::Declined) {
+        sqlx::query!(
+            r#"
+                UPDATE reservations SET cancelled_by = ?, cancel_reason = ? WHERE id = ?
+            "#,
+            actor.as_str(),
+            reason,
+            reservation_id
+        ).execute(&mut *tx).await?;
+    }
+
+    if next == ReservationStatus::PickedUp {
+        sqlx::query!(
+            r#"
+                UPDATE foods SET status = 'picked_up'
+                WHERE id = ? AND quantity_remaining = 0
+                AND NOT EXISTS (SELECT 1 FROM reservations WHERE food_id = ? AND status IN ('requested', 'confirmed'))
+            "#,
+            current.food_id,
+            current.food_id
+        ).execute(&mut *tx).await?;
+
+        sqlx::query!(
+            r#"
+                UPDATE users SET num_of_food_taken = num_of_food_taken + 1 WHERE id = ?
+            "#,
+            current.user_id
+        ).execute(&mut *tx).await?;
+
+    }
+
+    if next == ReservationStatus::NoShow {
+        sqlx::query!(
+            r#"
+                UPDATE users SET no_show_count = no_show_count + 1 WHERE id = ?
+            "#,
+            current.user_id
+        ).execute(&mut *tx).await?;
+    }
+
+    if next.releases_food() && status.holds_food() {
+        release_portions(&mut tx, current.food_id, current.quantity).await?;
+    }
+
+    let reservation = sqlx::query_as!(
+        ReservationDetails,
+        r#"
+            SELECT id, user_id, food_id, quantity, DATE_FORMAT(reserved_at, '%Y-%m-%d %H:%i:%s') as reserved_at, status,
+            cancelled_by, cancel_reason FROM reservations WHERE id = ?
+        "#,
+        reservation_id
+    ).fetch_one(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(reservation)
+}
+
+pub async fn get_pickup_secret(pool: &MySqlPool, reservation_id: i32) -> Result<Option<PickupSecret>, sqlx::Error>{
+    let secret = sqlx::query_as!(
+        PickupSecret,
+        r#"
+            SELECT r.user_id AS receiver_id, f.user_id AS "donor_id?", r.status, r.pickup_nonce,
+            r.expires_at AS "expires_at: DateTime<Utc>"
+            FROM reservations r INNER JOIN foods f ON f.id = r.food_id
+            WHERE r.id = ?
+        "#,
+        reservation_id
+    ).fetch_optional(pool).await?;
+
+    Ok(secret)
+}
+
+pub async fn get_reservation_members(pool: &MySqlPool, reservation_id: i32) -> Result<Option<ReservationMembers>, sqlx::Error>{
+    let members = sqlx::query_as!(
+        ReservationMembers,
+        r#"
+            SELECT r.user_id AS receiver_id, f.user_id AS "donor_id?", r.status
+            FROM reservations r INNER JOIN foods f ON f.id = r.food_id
+            WHERE r.id = ?
+        "#,
+        reservation_id
+    ).fetch_optional(pool).await?;
+
+    Ok(members)
+}
+
+pub async fn insert_message(pool: &MySqlPool, reservation_id: i32, sender_id: i32, body: &str) -> Result<Message, sqlx::Error>{
+    let id = sqlx::query!(
+        r#"
+            INSERT INTO reservation_messages (reservation_id, sender_id, body) VALUES (?, ?, ?)
+        "#,
+        reservation_id,
+        sender_id,
+        body
+    ).execute(pool).await?.last_insert_id();
+
+    let message = sqlx::query_as!(
+        Message,
+        r#"
+            SELECT id, sender_id, body, DATE_FORMAT(created_at, '%Y-%m-%d %H:%i:%s') AS sent_at,
+            DATE_FORMAT(read_at, '%Y-%m-%d %H:%i:%s') AS read_at
+            FROM reservation_messages WHERE id = ?
+        "#,
+        id
+    ).fetch_one(pool).await?;
+
+    Ok(message)
+}
+
+// newest first. whatever the other side sent is marked read, that is the read receipt they see
+pub async fn get_messages(pool: &MySqlPool, reservation_id: i32, reader_id: i32, params: &PageQuery) -> Result<Page<Message>, ApiError>{
+    let limit = page_limit(params.limit);
+    let before_id = match &params.cursor {
+        Some(cursor) => decode_cursor(cursor)
+            .and_then(|c| c.parse::<i32>().ok())
+            .ok_or_else(|| ApiError::BadRequest(format!("invalid cursor")))?,
+        None => i32::MAX
+    };
+
+    // only what this page shows counts as read, older messages wait until the reader scrolls to them
+    sqlx::query!(
+        r#"
+            UPDATE reservation_messages m
+            INNER JOIN (
+                SELECT id FROM reservation_messages WHERE reservation_id = ? AND id < ? ORDER BY id DESC LIMIT ?
+            ) page ON page.id = m.id
+            SET m.read_at = NOW()
+            WHERE m.sender_id <> ? AND m.read_at IS NULL
+        "#,
+        reservation_id,
+        before_id,
+        limit,
+        reader_id
+    ).execute(pool).await?;
+
+    let messages = sqlx::query_as!(
+        Message,
+        r#"
+            SELECT id, sender_id, body, DATE_FORMAT(created_at, '%Y-%m-%d %H:%i:%s') AS sent_at,
+            DATE_FORMAT(read_at, '%Y-%m-%d %H:%i:%s') AS read_at
+            FROM reservation_messages
+            WHERE reservation_id = ? AND id < ?
+            ORDER BY id DESC LIMIT ?
+        "#,
+        reservation_id,
+        before_id,
+        limit + 1
+    ).fetch_all(pool).await?;
+
+    Ok(Page::from_rows(messages, limit, |m| m.id.to_string()))
+}
+
+// takes one of the reservation's pickup code attempts, false once they are used up.
+// taken before the code is checked, so parallel guesses can't all slip in under the limit
+pub async fn take_pickup_attempt(pool: &MySqlPool, reservation_id: i32) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        r#"
+            UPDATE reservations SET pickup_code_attempts = pickup_code_attempts + 1
+            WHERE id = ? AND pickup_code_attempts < ?
+        "#,
+        reservation_id,
+        PICKUP_CODE_MAX_ATTEMPTS
+    ).execute(pool).await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn find_open_reservation(pool: &MySqlPool, user_id: i32, food_id: i32) -> Result<Option<i32>, sqlx::Error>{
+    let reservation_id = sqlx::query_scalar!(
+        r#"
+            SELECT id FROM reservations
+            WHERE user_id = ? AND food_id = ? AND status IN ('pending', 'requested', 'confirmed')
+            ORDER BY id DESC LIMIT 1
+        "#,
+        user_id,
+        food_id
+    ).fetch_optional(pool).await?;
+
+    Ok(reservation_id)
+}
+
+pub async fn get_reservation_parties(pool: &MySqlPool, reservation_id: i32) -> Result<ReservationParties, sqlx::Error>{
+    let parties = sqlx::query_as!(
+        ReservationParties,
+        r#"
+            SELECT r.user_id AS receiver_id, receiver.email AS receiver_email, donor.email AS donor_email, f.title AS food_title
+            FROM reservations r
+            INNER JOIN users receiver ON receiver.id = r.user_id
+            INNER JOIN foods f ON f.id = r.food_id
+            INNER JOIN users donor ON donor.id = f.user_id
+            WHERE r.id = ?
+        "#,
+        reservation_id
+    ).fetch_one(pool).await?;
+
+    Ok(parties)
+}
+
+// listed food whose best-before date has passed
+// spoiled food still on the listings, plus expired food a run gave up on before its reservations were all cancelled
+pub async fn spoiled_foods(pool: &MySqlPool, limit: i64) -> Result<Vec<SpoiledFood>, sqlx::Error>{
+    let spoiled = sqlx::query_as!(
+        SpoiledFood,
+        r#"
+            SELECT f.id, f.title, f.status AS "status?", u.email AS donor_email FROM foods f
+            INNER JOIN users u ON u.id = f.user_id
+            WHERE f.best_before <= NOW()
+            AND (
+                f.status IN ('active', 'reserved')
+                OR (f.status = 'expired' AND EXISTS (
+                    SELECT 1 FROM reservations r WHERE r.food_id = f.id AND r.status IN ('pending', 'requested', 'confirmed')
+                ))
+            )
+            ORDER BY f.best_before LIMIT ?
+        "#,
+        limit
+    ).fetch_all(pool).await?;
+
+    Ok(spoiled)
+}
+
+// false when someone else already took the food off the listings
+pub async fn mark_food_expired(pool: &MySqlPool, food_id: i32) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        r#"
+            UPDATE foods SET status = 'expired' WHERE id = ? AND status IN ('active', 'reserved')
+        "#,
+        food_id
+    ).execute(pool).await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn open_reservations_for_food(pool: &MySqlPool, food_id: i32) -> Result<Vec<i32>, sqlx::Error>{
+    let open = sqlx::query_scalar!(
+        r#"
+            SELECT id FROM reservations WHERE food_id = ? AND status IN ('pending', 'requested', 'confirmed')
+        "#,
+        food_id
+    ).fetch_all(pool).await?;
+
+    Ok(open)
+}
+
+// requests waiting on the donor, oldest first
+pub async fn get_pending_requests(pool: &MySqlPool, food_id: i32) -> Result<Vec<ReservationRequest>, sqlx::Error>{
+    let requests = sqlx::query_as!(
+        ReservationRequest,
+        r#"
+            SELECT r.id AS reservation_id, r.user_id, u.first_name, r.quantity,
+            DATE_FORMAT(r.reserved_at, '%Y-%m-%d %H:%i:%s') AS requested_at, u.no_show_count
+            FROM reservations r INNER JOIN users u ON u.id = r.user_id
+            WHERE r.food_id = ? AND r.status = 'pending'
+            ORDER BY r.id
+        "#,
+        food_id
+    ).fetch_all(pool).await?;
+
+    Ok(requests)
+}
+
+// pending requests that can't be met anymore with what is left of the food
+pub async fn unfit_requests(pool: &MySqlPool, food_id: i32) -> Result<Vec<i32>, sqlx::Error>{
+    let unfit = sqlx::query_scalar!(
+        r#"
+            SELECT r.id FROM reservations r INNER JOIN foods f ON f.id = r.food_id
+            WHERE r.food_id = ? AND r.status = 'pending'
+            AND (f.status <> 'active' OR r.quantity > f.quantity_remaining)
+        "#,
+        food_id
+    ).fetch_all(pool).await?;
+
+    Ok(unfit)
+}
+
+pub async fn waitlist_position(pool: &MySqlPool, food_id: i32, user_id: i32) -> Result<Option<i64>, sqlx::Error>{
+    let position = sqlx::query_scalar!(
+        r#"
+            SELECT (SELECT COUNT(*) FROM food_waitlist ahead WHERE ahead.food_id = w.food_id AND ahead.id <= w.id) AS "position!: i64"
+            FROM food_waitlist w WHERE w.food_id = ? AND w.user_id = ?
+        "#,
+        food_id,
+        user_id
+    ).fetch_optional(pool).await?;
+
+    Ok(position)
+}
+
+// only worth queueing for a food someone else holds, one without enough portions left, or one people already wait for
+pub async fn join_waitlist(pool: &MySqlPool, user_id: i32, food_id: i32, quantity: i32) -> Result<WaitlistSpot, ApiError>{
+    let mut tx = pool.begin().await?;
+
+    // the food row lock serialises everyone joining or leaving this food's line
+    let food = sqlx::query!(
+        r#"
+            SELECT user_id AS "user_id?", status AS "status?", quantity_remaining, max_per_user
+            FROM foods WHERE id = ? FOR UPDATE
+        "#,
+        food_id
+    ).fetch_optional(&mut *tx).await?;
+
+    let food = food.ok_or_else(|| ApiError::NotFound(format!("food not found")))?;
+    if food.user_id == Some(user_id) {
+        return Err(ApiError::BadRequest(format!("you can't wait for your own donation")));
+    }
+    let waiting = sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*) AS "waiting!: i64" FROM food_waitlist WHERE food_id = ? AND status = 'waiting'
+        "#,
+        food_id
+    ).fetch_one(&mut *tx).await?;
+    match food.status.as_deref() {
+        Some("active") if quantity <= food.quantity_remaining && waiting == 0 => {
+            return Err(ApiError::Conflict(format!("food is available, reserve it instead")));
+        }
+        Some("active") | Some("reserved") => {}
+        _ => return Err(ApiError::Conflict(format!("food is no longer available")))
+    }
+    if let Some(max_per_user) = food.max_per_user.filter(|max| quantity > *max) {
+        return Err(ApiError::Conflict(format!("you can claim at most {} portions of this food", max_per_user)));
+    }
+
+    let existing = sqlx::query_scalar!(
+        r#"
+            SELECT id FROM food_waitlist WHERE food_id = ? AND user_id = ?
+        "#,
+        food_id,
+        user_id
+    ).fetch_optional(&mut *tx).await?;
+    if existing.is_some() {
+        return Err(ApiError::Conflict(format!("you are already on the waitlist")));
+    }
+    let reserved = sqlx::query_scalar!(
+        r#"
+            SELECT id FROM reservations WHERE food_id = ? AND user_id = ? AND status IN ('pending', 'requested', 'confirmed')
+        "#,
+        food_id,
+        user_id
+    ).fetch_optional(&mut *tx).await?;
+    if reserved.is_some() {
+        return Err(ApiError::Conflict(format!("you already have an open reservation for this food")));
+    }
+
+    sqlx::query!(
+        r#"
+            INSERT INTO food_waitlist (food_id, user_id, quantity, status) VALUES (?, ?, ?, 'waiting')
+        "#,
+        food_id,
+        user_id,
+        quantity
+    ).execute(&mut *tx).await?;
+
+    let position = sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*) AS "position!: i64" FROM food_waitlist WHERE food_id = ?
+        "#,
+        food_id
+    ).fetch_one(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(WaitlistSpot { food_id, position })
+}
+
+// true when the caller was holding an offer, so the food has to be offered to the next person
+pub async fn leave_waitlist(pool: &MySqlPool, user_id: i32, food_id: i32) -> Result<bool, ApiError>{
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("SELECT id FROM foods WHERE id = ? FOR UPDATE", food_id).fetch_optional(&mut *tx).await?;
+    let spot = sqlx::query!(
+        r#"
+            SELECT status, quantity FROM food_waitlist WHERE food_id = ? AND user_id = ?
+        "#,
+        food_id,
+        user_id
+    ).fetch_optional(&mut *tx).await?;
+    let spot = spot.ok_or_else(|| ApiError::NotFound(format!("you are not on the waitlist")))?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM food_waitlist WHERE food_id = ? AND user_id = ?
+        "#,
+        food_id,
+        user_id
+    ).execute(&mut *tx).await?;
+
+    let was_offered = spot.status == "offered";
+    if was_offered {
+        release_portions(&mut tx, food_id, spot.quantity).await?;
+    }
+
+    tx.commit().await?;
+    Ok(was_offered)
+}
+
+// portions the caller asked for, if they hold a live offer on the food
+pub async fn get_waitlist_offer(pool: &MySqlPool, user_id: i32, food_id: i32) -> Result<Option<i32>, sqlx::Error>{
+    let quantity = sqlx::query_scalar!(
+        r#"
+            SELECT quantity FROM food_waitlist
+            WHERE food_id = ? AND user_id = ? AND status = 'offered' AND offer_expires_at > NOW()
+        "#,
+        food_id,
+        user_id
+    ).fetch_optional(pool).await?;
+
+    Ok(quantity)
+}
+
+// strictly first come first served: each offer claims just the portions that person queued for,
+// and when the head of the line wants more than is left, everyone behind them waits too
+pub async fn promote_waitlist(pool: &MySqlPool, food_id: i32) -> Result<Vec<WaitlistOffer>, sqlx::Error>{
+    let mut tx = pool.begin().await?;
+
+    let food = sqlx::query!(
+        r#"
+            SELECT status AS "status?", quantity_remaining, title FROM foods WHERE id = ? FOR UPDATE
+        "#,
+        food_id
+    ).fetch_optional(&mut *tx).await?;
+    let food = match food {
+        Some(food) if food.status.as_deref() == Some("active") => food,
+        _ => return Ok(Vec::new())
+    };
+
+    let waiting = sqlx::query!(
+        r#"
+            SELECT w.id, w.quantity, u.email FROM food_waitlist w
+            INNER JOIN users u ON u.id = w.user_id
+            WHERE w.food_id = ? AND w.status = 'waiting'
+            ORDER BY w.id
+        "#,
+        food_id
+    ).fetch_all(&mut *tx).await?;
+
+    let mut remaining = food.quantity_remaining;
+    let mut offers = Vec::new();
+    for next in waiting {
+        if next.quantity > remaining {
+            break;
+        }
+        sqlx::query!(
+            r#"
+                UPDATE food_waitlist SET status = 'offered', offer_expires_at = DATE_ADD(NOW(), INTERVAL ? MINUTE) WHERE id = ?
+            "#,
+            WAITLIST_OFFER_MINUTES,
+            next.id
+        ).execute(&mut *tx).await?;
+        claim_portions(&mut tx, food_id, next.quantity).await?;
+        remaining -= next.quantity;
+        offers.push(WaitlistOffer { email: next.email, food_title: food.title.clone() });
+    }
+
+    tx.commit().await?;
+    Ok(offers)
+}
+
+// foods whose offer ran out without an answer
+pub async fn lapsed_offers(pool: &MySqlPool, limit: i64) -> Result<Vec<i32>, sqlx::Error>{
+    let lapsed = sqlx::query_scalar!(
+        r#"
+            SELECT food_id FROM food_waitlist
+            WHERE status = 'offered' AND offer_expires_at <= NOW()
+            ORDER BY offer_expires_at LIMIT ?
+        "#,
+        limit
+    ).fetch_all(pool).await?;
+
+    Ok(lapsed)
+}
+
+// whoever let their offer lapse leaves the line and the portions it claimed go back.
+// false when the offers were accepted or dropped in the meantime
+pub async fn drop_lapsed_offer(pool: &MySqlPool, food_id: i32) -> Result<bool, sqlx::Error>{
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("SELECT id FROM foods WHERE id = ? FOR UPDATE", food_id).fetch_optional(&mut *tx).await?;
+    let lapsed = sqlx::query!(
+        r#"
+            SELECT id, quantity FROM food_waitlist
+            WHERE food_id = ? AND status = 'offered' AND offer_expires_at <= NOW()
+        "#,
+        food_id
+    ).fetch_all(&mut *tx).await?;
+    for offer in &lapsed {
+        sqlx::query!(
+            r#"
+                DELETE FROM food_waitlist WHERE id = ?
+            "#,
+            offer.id
+        ).execute(&mut *tx).await?;
+        release_portions(&mut tx, food_id, offer.quantity).await?;
+    }
+
+    tx.commit().await?;
+    Ok(!lapsed.is_empty())
+}
+
+pub async fn clear_waitlist(pool: &MySqlPool, food_id: i32) -> Result<(), sqlx::Error>{
+    sqlx::query!(
+        r#"
+            DELETE FROM food_waitlist WHERE food_id = ?
+        "#,
+        food_id
+    ).execute(pool).await?;
+
+    Ok(())
+}
+
+pub async fn due_reservations(pool: &MySqlPool, limit: i64) -> Result<Vec<(i32, String)>, sqlx::Error>{
+    let due = sqlx::query!(
+        r#"
+            SELECT id, status FROM reservations
+            WHERE status IN ('pending', 'requested', 'confirmed') AND expires_at < NOW()
+            ORDER BY expires_at LIMIT ?
+        "#,
+        limit
+    ).fetch_all(pool).await?;
+
+    Ok(due.into_iter().map(|r| (r.id, r.status)).collect())
+}
+
+// active food within radius_km of a point, closest first. the bounding box lets the index
+// throw away most rows before the exact great circle distance is worked out
+pub async fn get_nearby_food(pool: &MySqlPool, latitude: f64, longitude: f64, radius_km: f64, limit: i64) -> Result<Vec<NearbyFood>, sqlx::Error>{
+    let area = bounding_box(latitude, longitude, radius_km);
+    let nearby = sqlx::query_as::<_, NearbyFood>(
+        r#"
+            SELECT id, title, description, is_free, pickup_start, pickup_end, pickup_utc_offset, user_id,
+            (SELECT CONCAT('/media/', COALESCE(m.thumbnail_key, m.media_key)) FROM media m WHERE m.media_key = foods.image_key) as image_url, pickup_address, status,
+            latitude, longitude, category, quantity, quantity_remaining, max_per_user, requires_approval, best_before, TIMESTAMPDIFF(SECOND, NOW(), best_before) AS time_remaining_secs,
+            6371 * 2 * ASIN(SQRT(
+                POW(SIN(RADIANS(latitude - ?) / 2), 2) +
+                COS(RADIANS(?)) * COS(RADIANS(latitude)) * POW(SIN(RADIANS(longitude - ?) / 2), 2)
+            )) AS distance_km
+            FROM foods
+            WHERE status = 'active' AND (pickup_end IS NULL OR pickup_end > NOW())
+            AND latitude BETWEEN ? AND ? AND (longitude BETWEEN ? AND ? OR longitude BETWEEN ? AND ?)
+            HAVING distance_km <= ?
+            ORDER BY distance_km
+            LIMIT ?
+        "#
+    )
+    .bind(latitude)
+    .bind(latitude)
+    .bind(longitude)
+    .bind(area.min_lat)
+    .bind(area.max_lat)
+    .bind(area.lng_ranges[0].0)
+    .bind(area.lng_ranges[0].1)
+    .bind(area.lng_ranges[1].0)
+    .bind(area.lng_ranges[1].1)
+    .bind(radius_km)
+    .bind(limit)
+    .fetch_all(pool).await?;
+
+    Ok(nearby)
+}
+
+pub async fn insert_media(pool: &MySqlPool, key: &str, owner_id: i32, content_type: &str, byte_size: i64, thumbnail_key: Option<&str>) -> Result<(), sqlx::Error>{
+    // the same bytes uploaded twice are the same file, the first uploader stays the owner
+    sqlx::query!(
+        r#"
+            INSERT IGNORE INTO media (media_key, owner_id, content_type, byte_size, thumbnail_key) VALUES (?, ?, ?, ?, ?)
+        "#,
+        key,
+        owner_id,
+        content_type,
+        byte_size,
+        thumbnail_key
+    ).execute(pool).await?;
+
+    // but whoever uploaded it may use it
+    sqlx::query!(
+        r#"
+            INSERT IGNORE INTO media_uploads (media_key, user_id) VALUES (?, ?)
+        "#,
+        key,
+        owner_id
+    ).execute(pool).await?;
+
+    Ok(())
+}
+
+pub async fn get_media_content_type(pool: &MySqlPool, key: &str) -> Result<Option<String>, sqlx::Error>{
+    let content_type = sqlx::query_scalar!(
+        r#"
+            SELECT content_type FROM media WHERE media_key = ?
+        "#,
+        key
+    ).fetch_optional(pool).await?;
+
+    Ok(content_type)
+}
+
+pub async fn has_uploaded_media(pool: &MySqlPool, key: &str, user_id: i32) -> Result<bool, sqlx::Error>{
+    let uploaded = sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS(SELECT 1 FROM media_uploads WHERE media_key = ? AND user_id = ?) AS "uploaded!: bool"
+        "#,
+        key,
+        user_id
+    ).fetch_one(pool).await?;
+
+    Ok(uploaded)
+}
+
+// walks the table by id, so rows that get skipped aren't fetched again
+pub async fn blob_food_images(pool: &MySqlPool, after_id: i32, limit: i64) -> Result<Vec<(i32, i32, Vec<u8>)>, sqlx::Error>{
+    let rows = sqlx::query!(
+        r#"
+            SELECT id, user_id AS "user_id!", image AS "image!" FROM foods
+            WHERE image IS NOT NULL AND image_key IS NULL AND user_id IS NOT NULL AND id > ?
+            ORDER BY id
+            LIMIT ?
+        "#,
+        after_id,
+        limit
+    ).fetch_all(pool).await?;
+
+    Ok(rows.into_iter().map(|r| (r.id, r.user_id, r.image)).collect())
+}
+
+pub async fn set_food_image_key(pool: &MySqlPool, food_id: i32, key: &str) -> Result<(), sqlx::Error>{
+    sqlx::query!(
+        r#"
+            UPDATE foods SET image_key = ?, image = NULL WHERE id = ?
+        "#,
+        key,
+        food_id
+    ).execute(pool).await?;
+
+    Ok(())
+}
+
+pub async fn blob_profile_images(pool: &MySqlPool, after_id: i32, limit: i64) -> Result<Vec<(i32, Vec<u8>)>, sqlx::Error>{
+    let rows = sqlx::query!(
+        r#"
+            SELECT id, profile_image AS "profile_image!" FROM users
+            WHERE profile_image IS NOT NULL AND profile_image_key IS NULL AND id > ?
+            ORDER BY id
+            LIMIT ?
+        "#,
+        after_id,
+        limit
+    ).fetch_all(pool).await?;
+
+    Ok(rows.into_iter().map(|r| (r.id, r.profile_image)).collect())
+}
+
+pub async fn set_profile_image_key(pool: &MySqlPool, user_id: i32, key: &str) -> Result<(), sqlx::Error>{
+    sqlx::query!(
+        r#"
+            UPDATE users SET profile_image_key = ?, profile_image = NULL WHERE id = ?
+        "#,
+        key,
+        user_id
+    ).execute(pool).await?;
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use sqlx::MySqlPool;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tokio::sync::Mutex;
+
+use crate::db::{get_feed_food, FeedFood, FeedQuery};
+use crate::errors::ApiError;
+use crate::geo::{haversine_km, valid_coordinates, DEFAULT_RADIUS_KM, MAX_RADIUS_KM};
+use crate::taxonomy::{parse_list, Category};
+
+// how far a slow subscriber can fall behind before it starts missing events
+const FEED_CAPACITY: usize = 256;
+// proxies drop connections that stay quiet for too long
+const HEARTBEAT: Duration = Duration::from_secs(15);
+// foods share these locks by id, enough that unrelated foods rarely wait on each other
+const FOOD_LOCKS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedEventKind {
+    Created,
+    Updated,
+    Reserved,
+    Deleted,
+}
+
+impl FeedEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FeedEventKind::Created => "created",
+            FeedEventKind::Updated => "updated",
+            FeedEventKind::Reserved => "reserved",
+            FeedEventKind::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct FeedEvent {
+    pub id: u64,
+    pub kind: FeedEventKind,
+    pub food: FeedFood,
+}
+
+impl FeedEvent {
+    // one server-sent event, the id lets clients notice a gap
+    fn frame(&self) -> String {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, self.kind.as_str(), data)
+    }
+}
+
+/// Fans food changes out to everyone connected to `GET /feed`.
+/// Kept in memory, so subscribers only hear about changes made on the same instance.
+pub struct FeedBroadcaster {
+    sender: Sender<FeedEvent>,
+    last_id: Mutex<u64>,
+    food_locks: Vec<Mutex<()>>,
+}
+
+impl Default for FeedBroadcaster {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        let food_locks = (0..FOOD_LOCKS).map(|_| Mutex::new(())).collect();
+        FeedBroadcaster { sender, last_id: Mutex::new(0), food_locks }
+    }
+}
+
+impl FeedBroadcaster {
+    pub fn subscribe(&self) -> Receiver<FeedEvent> {
+        self.sender.subscribe()
+    }
+
+    // ids are handed out and sent under the same lock, so every subscriber sees them in increasing order
+    pub async fn publish(&self, kind: FeedEventKind, food: FeedFood) {
+        let mut last_id = self.last_id.lock().await;
+        self.send(&mut last_id, kind, food);
+    }
+
+    fn send(&self, last_id: &mut u64, kind: FeedEventKind, food: FeedFood) {
+        *last_id += 1;
+        // an error only means nobody is listening right now
+        let _ = self.sender.send(FeedEvent { id: *last_id, kind, food });
+    }
+
+    fn food_lock(&self, food_id: i32) -> &Mutex<()> {
+        &self.food_locks[food_id.unsigned_abs() as usize % FOOD_LOCKS]
+    }
+}
+
+// the food as it is now, None when it is gone or couldn't be loaded
+pub async fn snapshot(pool: &MySqlPool, food_id: i32) -> Option<FeedFood> {
+    match get_feed_food(pool, food_id).await {
+        Ok(food) => food,
+        Err(err) => {
+            log::error!("couldn't load food {} for the feed: {}", food_id, err);
+            None
+        }
+    }
+}
+
+/// Tells subscribers about a food that changed. Like the mails, a failure here is only logged.
+/// The food is loaded and sent under its own lock, so a later id never goes out with an older state of it,
+/// while the id lock is only held to number and send the event.
+pub async fn publish_food(pool: &MySqlPool, feed: &FeedBroadcaster, kind: FeedEventKind, food_id: i32) {
+    let _food_lock = feed.food_lock(food_id).lock().await;
+    if let Some(food) = snapshot(pool, food_id).await {
+        feed.publish(kind, food).await;
+    }
+}
+
+/// What one subscriber wants to hear about. Filtering happens here rather than in the
+/// client so a phone watching one neighbourhood isn't sent the whole city.
+pub struct FeedFilter {
+    area: Option<(f64, f64, f64)>,
+    categories: Vec<Category>,
+}
+
+impl FeedFilter {
+    pub fn from_query(query: &FeedQuery) -> Result<Self, ApiError> {
+        let area = match (query.lat, query.lng) {
+            (None, None) => None,
+            (Some(lat), Some(lng)) => {
+                if !valid_coordinates(lat, lng) {
+                    return Err(ApiError::BadRequest(format!("lat must be within -90..90 and lng within -180..180")));
+                }
+                let radius_km = query.radius_km.unwrap_or(DEFAULT_RADIUS_KM);
+                if !radius_km.is_finite() || radius_km <= 0.0 || radius_km > MAX_RADIUS_KM {
+                    return Err(ApiError::BadRequest(format!("radius_km must be between 0 and {}", MAX_RADIUS_KM)));
+                }
+                Some((lat, lng, radius_km))
+            }
+            _ => return Err(ApiError::BadRequest(format!("lat and lng must be sent together"))),
+        };
+        let categories = match &query.category {
+            Some(list) => parse_list(list, Category::parse).map_err(|err| ApiError::BadRequest(format!("category: {}", err)))?,
+            None => Vec::new(),
+        };
+        Ok(FeedFilter { area, categories })
+    }
+
+    // foods without coordinates can't be placed in an area, so an area filter leaves them out
+    pub fn matches(&self, food: &FeedFood) -> bool {
+        if let Some((lat, lng, radius_km)) = self.area {
+            match (food.latitude, food.longitude) {
+                (Some(food_lat), Some(food_lng)) if haversine_km(lat, lng, food_lat, food_lng) <= radius_km => {}
+                _ => return false,
+            }
+        }
+        self.categories.is_empty()
+            || food.category.as_deref().and_then(Category::parse).is_some_and(|category| self.categories.contains(&category))
+    }
+}
+
+/// The body of a `GET /feed` response. The first heartbeat goes out straight away,
+/// which gets the headers to the client before anything has happened.
+pub fn event_stream(receiver: Receiver<FeedEvent>, filter: FeedFilter) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let heartbeat = tokio::time::interval(HEARTBEAT);
+    futures_util::stream::unfold((receiver, filter, heartbeat), |(mut receiver, filter, mut heartbeat)| async move {
+        loop {
+            let frame = tokio::select! {
+                received = receiver.recv() => match received {
+                    Ok(event) if filter.matches(&event.food) => event.frame(),
+                    Ok(_) => continue,
+                    // it fell too far behind, the client reloads the list instead of trusting a feed with holes
+                    Err(RecvError::Lagged(missed)) => format!("event: resync\ndata: {{\"missed\":{}}}\n\n", missed),
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = heartbeat.tick() => ": keep-alive\n\n".to_string(),
+            };
+            return Some((Ok(Bytes::from(frame)), (receiver, filter, heartbeat)));
+        }
+    })
+}
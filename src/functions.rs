@@ -1,4 +1,4 @@
-use actix_web::HttpResponse;
+use actix_web::{HttpResponse, ResponseError};
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use argon2::password_hash::SaltString;
 // use lettre::message;
@@ -8,9 +8,12 @@ use lettre::{Message, SmtpTransport, Transport, message::header::ContentType};
 use lettre::transport::smtp::authentication::Credentials;
 use serde::Serialize;
 use std::env;
+use std::sync::LazyLock;
 use dotenvy::dotenv;
 
+use crate::auth::PASSWORD_RESET_TTL_MINUTES;
 use crate::db::ApiResponse;
+use crate::errors::ApiError;
 
 pub fn hash_password(password: String) -> String {
     let salt = SaltString::generate(&mut OsRng);
@@ -29,6 +32,19 @@ pub fn compare_password(inputted_password: &str, db_password: &str) -> bool {
     }
 }
 
+// a hash of a password nobody has, checked when the email has no account so that costs as much as a wrong password
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| hash_password("no account has this password".to_string()));
+
+// hashed once at startup, otherwise the first unknown email would take twice as long
+pub fn prepare_dummy_password() {
+    LazyLock::force(&DUMMY_PASSWORD_HASH);
+}
+
+pub fn compare_dummy_password(inputted_password: &str) -> bool {
+    compare_password(inputted_password, &DUMMY_PASSWORD_HASH);
+    false
+}
+
 pub fn compare_email(inputted_email:&str, db_email: &str) -> bool {
     inputted_email.to_string().eq(db_email)
 }
@@ -40,31 +56,16 @@ pub fn generate_code() -> String {
 }
 
 
-pub async fn send_mail(user_mail: &String, code: &String) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn send_html_mail(user_mail: &str, subject: &str, body: String) -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
     let username = env::var("EMAIL_USERNAME").expect("email username does not exist");
     let password = env::var("EMAIL_PASSWORD").expect("password not exist");
     let relay = env::var("EMAIL_SMTP").expect("smtp does not exist");
 
-    let body = format!(r#"
-        <html>
-            <body>
-                <div style="font-family: Arial; padding: 20px;">
-                <h2 style="color: #2e7d32;">Verify Your Email - Avanzo</h2>
-                <p>Thanks for signing up! Your verification code is:</p>
-                <div style="font-size: 24px; font-weight: bold; background-color: #e8f5e9; padding: 10px; color: #1b5e20; border-radius: 8px;">
-                    {code}
-                </div>
-                <p>If you didn’t request this, just ignore it.</p>
-            </div>
-            </body>
-        </html>
-    "#, code = code);
-    // println!("{}", body);
     let email = Message::builder()
         .from("Avanzo app <ritrove@ritrove.com>".parse()?)
         .to(user_mail.parse()?)
-        .subject("Verify your name")
+        .subject(subject)
         .header(ContentType::TEXT_HTML)
         .body(body)?;
 
@@ -74,26 +75,32 @@ pub async fn send_mail(user_mail: &String, code: &String) -> Result<(), Box<dyn
     .credentials(creds)
     .build();
 
-    mailer.send(&email)?;
+    // the smtp client blocks, keep it off the worker that serves requests
+    tokio::task::spawn_blocking(move || mailer.send(&email)).await??;
 
     Ok(())
 }
 
-pub fn check_code(code_user: &String, code_db: String) -> bool{
-    if code_db.eq(code_user) {
-        return true; 
-    }
-    false
+pub async fn send_mail(user_mail: &String, code: &String) -> Result<(), Box<dyn std::error::Error>> {
+    let body = format!(r#"
+        <html>
+            <body>
+                <div style="font-family: Arial; padding: 20px;">
+                <h2 style="color: #2e7d32;">Verify Your Email - Avanzo</h2>
+                <p>Thanks for signing up! Your verification code is:</p>
+                <div style="font-size: 24px; font-weight: bold; background-color: #e8f5e9; padding: 10px; color: #1b5e20; border-radius: 8px;">
+                    {code}
+                </div>
+                <p>If you didn’t request this, just ignore it.</p>
+            </div>
+            </body>
+        </html>
+    "#, code = code);
+
+    send_html_mail(user_mail, "Verify your name", body).await
 }
 
 pub async fn send_goodbye_mail(user_mail: String) -> Result<(), Box<dyn std::error::Error>>{
-
-    dotenv().ok();
-    let username = env::var("EMAIL_USERNAME").expect("email username does not exist");
-    let password = env::var("EMAIL_PASSWORD").expect("password not exist");
-    let relay = env::var("EMAIL_SMTP").expect("smtp does not exist");
-    // println!("{username}");
-    // println!("{password}");
     let body = r#"
         <html>
             <body>
@@ -105,22 +112,130 @@ pub async fn send_goodbye_mail(user_mail: String) -> Result<(), Box<dyn std::err
             </body>
         </html>
     "#;
-    let email = Message::builder()
-        .from("Avanzo app <ritrove@ritrove.com>".parse()?)
-        .to(user_mail.parse()?)
-        .subject("Goodbye message")
-        .header(ContentType::TEXT_HTML)
-        .body(body.to_string())?;
 
-    let creds = Credentials::new(username, password);
+    send_html_mail(&user_mail, "Goodbye message", body.to_string()).await
+}
 
-    let mailer = SmtpTransport::relay(&relay)?
-    .credentials(creds)
-    .build();
+pub async fn send_reset_mail(user_mail: &str, token: &str) -> Result<(), Box<dyn std::error::Error>>{
+    let body = format!(r#"
+        <html>
+            <body>
+                <div style="font-family: Arial; padding: 20px;">
+                    <h2 style="color: #2e7d32;">Reset your password - Avanzo</h2>
+                    <p>Use this token to choose a new password. It expires in {minutes} minutes and works only once:</p>
+                    <div style="font-size: 16px; font-weight: bold; background-color: #e8f5e9; padding: 10px; color: #1b5e20; border-radius: 8px; word-break: break-all;">
+                        {token}
+                    </div>
+                    <p>If you didn’t ask for a reset, just ignore it, your password stays the same.</p>
+                </div>
+            </body>
+        </html>
+    "#, token = token, minutes = PASSWORD_RESET_TTL_MINUTES);
 
-    mailer.send(&email)?;
+    send_html_mail(user_mail, "Reset your password", body).await
+}
 
-    Ok(())
+pub async fn send_lockout_mail(user_mail: &str) -> Result<(), Box<dyn std::error::Error>>{
+    let body = r#"
+        <html>
+            <body>
+                <div style='font-family: Arial; padding: 20px;'>
+                    <h2 style='color: #2e7d32;'>Sign in paused - Avanzo</h2>
+                    <p>There were several failed attempts to sign in to your account, so we paused sign in for a little while.</p>
+                    <p>If this wasn’t you, consider resetting your password.</p>
+                </div>
+            </body>
+        </html>
+    "#;
+
+    send_html_mail(user_mail, "Too many sign in attempts", body.to_string()).await
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub async fn send_cancellation_mail(user_mail: &str, food_title: &str, cancelled_by: &str, reason: Option<&str>) -> Result<(), Box<dyn std::error::Error>>{
+    let reason = match reason {
+        Some(reason) => format!("<p>Reason given: {}</p>", escape_html(reason)),
+        None => String::new()
+    };
+    let body = format!(r#"
+        <html>
+            <body>
+                <div style='font-family: Arial; padding: 20px;'>
+                    <h2 style='color: #2e7d32;'>Reservation cancelled - Avanzo</h2>
+                    <p>The reservation for <b>{title}</b> was cancelled by {cancelled_by}.</p>
+                    {reason}
+                </div>
+            </body>
+        </html>
+    "#, title = escape_html(food_title), cancelled_by = cancelled_by, reason = reason);
+
+    send_html_mail(user_mail, "Reservation cancelled", body).await
+}
+
+pub async fn send_food_expired_mail(user_mail: &str, food_title: &str, cancelled: usize) -> Result<(), Box<dyn std::error::Error>>{
+    let reservations = match cancelled {
+        0 => String::new(),
+        1 => format!("<p>The reservation that was open on it has been cancelled.</p>"),
+        n => format!("<p>The {} reservations that were open on it have been cancelled.</p>", n)
+    };
+    let body = format!(r#"
+        <html>
+            <body>
+                <div style='font-family: Arial; padding: 20px;'>
+                    <h2 style='color: #2e7d32;'>Donation expired - Avanzo</h2>
+                    <p><b>{title}</b> reached its best-before date, so we took it off the listings.</p>
+                    {reservations}
+                </div>
+            </body>
+        </html>
+    "#, title = escape_html(food_title), reservations = reservations);
+
+    send_html_mail(user_mail, "Your donation expired", body).await
+}
+
+pub async fn send_waitlist_offer_mail(user_mail: &str, food_title: &str, food_id: i32, minutes: i32) -> Result<(), Box<dyn std::error::Error>>{
+    let body = format!(r#"
+        <html>
+            <body>
+                <div style='font-family: Arial; padding: 20px;'>
+                    <h2 style='color: #2e7d32;'>It's your turn - Avanzo</h2>
+                    <p><b>{title}</b> is available again and you are first on the waitlist.</p>
+                    <p>It is held for you for {minutes} minutes. Accept it in the app (food #{food_id}) before then or it goes to the next person in line.</p>
+                </div>
+            </body>
+        </html>
+    "#, title = escape_html(food_title), minutes = minutes, food_id = food_id);
+
+    send_html_mail(user_mail, "A food you waited for is available", body).await
+}
+
+// by_donor is false when the request was declined for the donor because what was left couldn't cover it
+pub async fn send_request_declined_mail(user_mail: &str, food_title: &str, by_donor: bool) -> Result<(), Box<dyn std::error::Error>>{
+    let reason = if by_donor {
+        "can't give it to you this time"
+    }else{
+        "accepted other requests and what is left isn't enough for yours"
+    };
+    let body = format!(r#"
+        <html>
+            <body>
+                <div style='font-family: Arial; padding: 20px;'>
+                    <h2 style='color: #2e7d32;'>Request declined - Avanzo</h2>
+                    <p>The donor of <b>{title}</b> {reason}.</p>
+                    <p>There is plenty more food around, have a look at what is near you.</p>
+                </div>
+            </body>
+        </html>
+    "#, title = escape_html(food_title), reason = reason);
+
+    send_html_mail(user_mail, "Your request was declined", body).await
 }
 
 pub fn success<T: Serialize>(message: &str, data: T) -> HttpResponse{
@@ -133,12 +248,6 @@ pub fn success<T: Serialize>(message: &str, data: T) -> HttpResponse{
     )
 }
 
-pub fn failure(messages: String) -> HttpResponse{
-    HttpResponse::InternalServerError().json(
-        ApiResponse::<()> {
-            success: false,
-            message: messages,
-            data: None
-        }
-    )
-}
\ No newline at end of file
+pub fn failure(err: ApiError) -> HttpResponse{
+    err.error_response()
+}
@@ -0,0 +1,224 @@
+const KM_PER_DEGREE_LAT: f64 = 111.045;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+pub const DEFAULT_RADIUS_KM: f64 = 5.0;
+pub const MAX_RADIUS_KM: f64 = 50.0;
+
+pub fn valid_coordinates(latitude: f64, longitude: f64) -> bool {
+    (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude)
+}
+
+/// Box around a point that contains every point within `radius_km`,
+/// cheap to check against an index before the exact distance is computed.
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub max_lat: f64,
+    // a box that crosses the antimeridian is two ranges, one either side of it.
+    // when it doesn't, both entries are the same range
+    pub lng_ranges: [(f64, f64); 2],
+}
+
+pub fn bounding_box(latitude: f64, longitude: f64, radius_km: f64) -> BoundingBox {
+    let lat_delta = radius_km / KM_PER_DEGREE_LAT;
+    // degrees of longitude shrink towards the poles, don't let the divisor hit zero
+    let lng_delta = radius_km / (KM_PER_DEGREE_LAT * latitude.to_radians().cos().max(0.01));
+    let min_lat = latitude - lat_delta;
+    let max_lat = latitude + lat_delta;
+    let min_lng = longitude - lng_delta;
+    let max_lng = longitude + lng_delta;
+    // a circle around a pole takes in every longitude
+    let lng_ranges = if lng_delta >= 180.0 || min_lat <= -90.0 || max_lat >= 90.0 {
+        [(-180.0, 180.0); 2]
+    }else if min_lng < -180.0 {
+        [(min_lng + 360.0, 180.0), (-180.0, max_lng)]
+    }else if max_lng > 180.0 {
+        [(min_lng, 180.0), (-180.0, max_lng - 360.0)]
+    }else{
+        [(min_lng, max_lng); 2]
+    };
+    BoundingBox {
+        min_lat: min_lat.max(-90.0),
+        max_lat: max_lat.min(90.0),
+        lng_ranges,
+    }
+}
+
+// great circle distance, the same formula get_nearby_food runs in sql
+pub fn haversine_km(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians() / 2.0;
+    let d_lng = (lng2 - lng1).to_radians() / 2.0;
+    let a = d_lat.sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * d_lng.sin().powi(2);
+    EARTH_RADIUS_KM * 2.0 * a.sqrt().asin()
+}
+
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// What happened to the coordinates of a donation when it was saved.
+#[derive(serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum GeocodeOutcome {
+    Provided,
+    Found { matched: String },
+    Failed { reason: String },
+}
+
+/// Turns a pickup address into coordinates.
+pub trait Geocoder: Send + Sync {
+    // the coordinates plus a label of what the address was matched against
+    fn geocode(&self, address: &str) -> Result<(Coordinates, String), String>;
+}
+
+// the form addresses are stored in: single spaces, no stray separators at the ends
+pub fn normalize_address(address: &str) -> String {
+    address
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c: char| c == ',' || c == ';' || c == ' ')
+        .to_string()
+}
+
+// lowercase words without punctuation and with the usual abbreviations spelled out, only used for matching
+fn match_key(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| match word {
+            "st" | "str" => "street",
+            "rd" => "road",
+            "ave" | "av" => "avenue",
+            "sq" => "square",
+            "pza" => "piazza",
+            "v" => "via",
+            "cso" => "corso",
+            other => other,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+struct GazetteerEntry {
+    street: String,
+    postcode: String,
+    city: String,
+    label: String,
+    coordinates: Coordinates,
+}
+
+/// Geocoder backed by a CSV file loaded in memory, so no outside service is needed.
+/// Each line is `street,postcode,city,latitude,longitude`, a header line is skipped.
+#[derive(Default)]
+pub struct Gazetteer {
+    entries: Vec<GazetteerEntry>,
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields.into_iter().map(|f| f.trim().to_string()).collect()
+}
+
+impl Gazetteer {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut entries = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let fields = split_csv_line(line);
+            if line.trim().is_empty() || fields.len() < 5 {
+                continue;
+            }
+            let (latitude, longitude) = match (fields[3].parse::<f64>(), fields[4].parse::<f64>()) {
+                (Ok(lat), Ok(lng)) if valid_coordinates(lat, lng) => (lat, lng),
+                // the header, or a broken row
+                _ => {
+                    if number > 0 {
+                        log::warn!("gazetteer line {} skipped: bad coordinates", number + 1);
+                    }
+                    continue;
+                }
+            };
+            entries.push(GazetteerEntry {
+                street: match_key(&fields[0]),
+                postcode: match_key(&fields[1]).replace(' ', ""),
+                city: match_key(&fields[2]),
+                label: format!("{}, {} {}", fields[0], fields[1], fields[2]),
+                coordinates: Coordinates { latitude, longitude },
+            });
+        }
+        Ok(Gazetteer { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Geocoder for Gazetteer {
+    fn geocode(&self, address: &str) -> Result<(Coordinates, String), String> {
+        if self.is_empty() {
+            return Err(format!("no gazetteer loaded"));
+        }
+        let key = match_key(address);
+        let padded = format!(" {} ", key);
+        let contains = |part: &str| !part.is_empty() && padded.contains(&format!(" {} ", part));
+        let words: Vec<&str> = key.split(' ').collect();
+        // the postcode has to be whole words of the address, though it may be written with spaces in it,
+        // so a house number like 1001845 doesn't pass for 00184
+        let has_postcode = |postcode: &str| {
+            !postcode.is_empty() && (0..words.len()).any(|start| {
+                let mut joined = String::new();
+                words[start..].iter().any(|word| {
+                    joined.push_str(word);
+                    joined == postcode
+                })
+            })
+        };
+
+        // best match wins: street with postcode, street with city, then the postcode area alone
+        let score = |entry: &GazetteerEntry| {
+            let street = contains(&entry.street);
+            let postcode = has_postcode(&entry.postcode);
+            let city = contains(&entry.city);
+            match (street, postcode, city) {
+                (true, true, _) => 4,
+                (true, false, true) => 3,
+                (false, true, _) => 1,
+                _ => 0,
+            }
+        };
+
+        let best = self.entries.iter()
+            .map(|entry| (score(entry), entry))
+            .filter(|(score, _)| *score > 0)
+            .max_by_key(|(score, _)| *score);
+
+        match best {
+            Some((_, entry)) => Ok((
+                Coordinates { latitude: entry.coordinates.latitude, longitude: entry.coordinates.longitude },
+                entry.label.clone(),
+            )),
+            None => Err(format!("address not found in gazetteer")),
+        }
+    }
+}
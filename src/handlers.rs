@@ -1,9 +1,12 @@
 
-use actix_web::{delete, get, patch, post, web::{self}, Responder};
+use actix_multipart::Multipart;
+use actix_web::{delete, get, http::header, patch, post, web::{self}, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, FixedOffset, Utc};
+use futures_util::StreamExt;
 // use rand::rand_core::impls;
 // use rand::rand_core::impls;
 use sqlx::{MySqlPool};
-use crate::{db::{add_user_code, change_email_verified, check_if_email_exists, check_if_user_has_reserve, create_new_user, delete_food, delete_user_account, delete_verification_code, edit_profile_picture, edit_reservation, edit_user_profile, get_active_donation, get_active_reserve, get_all_donations, get_all_food, get_email, get_food_detail, get_reservation_details, get_user_email, get_user_profile, get_user_reservations, increment_user_food_count, insert_food, login_user, make_reserve, mark_user_reserve, update_donation, update_verified, verify_user_code, EditUserDetails, FoodDetail, FoodDetail2, LoginDetail, NewUserDetails, PictureDetails, PicturePayload, ReserveDetails, UserCodeDetails}, functions::{compare_password, failure, generate_code, send_goodbye_mail, send_mail, success}};
+use crate::{auth::{authorize, generate_token, hash_verification_code, pickup_code, pickup_qr_payload, is_pickup_qr, verify_pickup, require_admin, hash_token, issue_access_token, AuthUser, Owned, ACCESS_TOKEN_TTL_SECS, PASSWORD_RESET_COOLDOWN_SECS, VERIFY_CODE_RESEND_COOLDOWN_SECS}, db::{check_verification_code, create_password_reset, seconds_since_last_code, seconds_since_last_reset, store_verification_code, CodeCheck, create_session, get_active_user_id, reset_password, ForgotPasswordPayload, ResetPasswordPayload, revoke_session, rotate_session, LoginResponse, RefreshPayload, SessionTokens, change_email_verified, check_if_email_exists, create_new_user, create_reservation, delete_food, delete_user_account, edit_profile_picture, edit_user_profile, find_open_reservation, get_reservation_parties, get_active_donation, get_active_reserve, get_all_donations, get_all_food, get_email, get_food_profile, get_pending_requests, unfit_requests, get_waitlist_offer, join_waitlist, leave_waitlist, get_media_content_type, has_uploaded_media, get_nearby_food, get_user_email, get_user_profile, get_user_reservations, increment_user_food_count, take_pickup_attempt, get_pickup_secret, get_reservation_members, get_messages, insert_message, insert_food, login_user, transition_reservation, update_donation, EditUserDetails, FoodDetail, FoodDetail2, LoginDetail, NewUserDetails, PictureDetails, PicturePayload, CancelPayload, EditedFood, FoodQuery, NearbyQuery, NewFood, PageQuery, ReservePayload, UserCodeDetails, WaitlistPayload, PickupCode, PickupPayload, MessagePayload, MessageThread, ReservationMembers, FeedQuery}, errors::ApiError, feed::{event_stream, publish_food, snapshot, FeedBroadcaster, FeedEventKind, FeedFilter}, media::{max_upload_bytes, save_image, sniff_content_type, valid_key, MediaStore, ACCEPTED_CONTENT_TYPES, CACHE_CONTROL}, geo::{normalize_address, valid_coordinates, GeocodeOutcome, Geocoder, DEFAULT_RADIUS_KM, MAX_RADIUS_KM}, reservation::{ReservationPolicy, ReservationStatus}, waitlist::offer_next, functions::{compare_dummy_password, compare_password, failure, generate_code, send_goodbye_mail, send_lockout_mail, send_cancellation_mail, send_mail, send_request_declined_mail, send_reset_mail, success}, throttle::{account_key, ip_key, LoginThrottle, RateLimiter, MAX_ACCOUNT_FAILURES, MAX_IP_FAILURES}};
 
 #[derive(serde::Deserialize)]
 struct FoodId{
@@ -13,31 +16,138 @@ struct FoodId{
 // struct UserId{
 //     user_id: i32
 // }
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_CANCEL_REASON_LENGTH: usize = 500;
+const MAX_MESSAGE_LENGTH: usize = 1000;
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct MajesticRes{
     pub user_email: String
 }
 
 #[get("/foods")] // tested
-async fn get_food_list(pool: web::Data<MySqlPool>) -> impl Responder{
-    match get_all_food(&pool).await {
+async fn get_food_list(pool: web::Data<MySqlPool>, query: web::Query<FoodQuery>) -> impl Responder{
+    match get_all_food(&pool, &query, Some("active")).await {
         Ok(food) => success("sucessfull", food),
-        Err(err) => failure(format!("failed to get food list: {}", err)) 
+        Err(err) => failure(err) 
+    }
+}
+
+#[get("/foods/nearby")]
+async fn get_food_nearby(pool: web::Data<MySqlPool>, query: web::Query<NearbyQuery>) -> impl Responder{
+    if !valid_coordinates(query.lat, query.lng) {
+        return failure(ApiError::BadRequest(format!("lat must be within -90..90 and lng within -180..180")));
+    }
+    let radius_km = query.radius_km.unwrap_or(DEFAULT_RADIUS_KM);
+    if !radius_km.is_finite() || radius_km <= 0.0 || radius_km > MAX_RADIUS_KM {
+        return failure(ApiError::BadRequest(format!("radius_km must be between 0 and {}", MAX_RADIUS_KM)));
+    }
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    match get_nearby_food(&pool, query.lat, query.lng, radius_km, limit).await {
+        Ok(food) => success("successfull", food),
+        Err(err) => failure(ApiError::db("failed to get nearby food", err))
+    }
+}
+
+// server-sent events for every food change, optionally only the ones near a point or in some categories.
+// a client that gets a resync event missed some and should reload the list
+#[get("/feed")]
+async fn food_feed(feed: web::Data<FeedBroadcaster>, query: web::Query<FeedQuery>) -> impl Responder{
+    let filter = match FeedFilter::from_query(&query) {
+        Ok(filter) => filter,
+        Err(err) => return failure(err)
+    };
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(event_stream(feed.subscribe(), filter))
+}
+
+// a donation either has both coordinates or none, and they have to be on the planet
+fn check_coordinates(latitude: Option<f64>, longitude: Option<f64>) -> Result<(), ApiError>{
+    match (latitude, longitude) {
+        (None, None) => Ok(()),
+        (Some(lat), Some(lng)) if valid_coordinates(lat, lng) => Ok(()),
+        (Some(_), Some(_)) => Err(ApiError::Unprocessable(format!("latitude must be within -90..90 and longitude within -180..180"))),
+        _ => Err(ApiError::Unprocessable(format!("latitude and longitude must be sent together")))
+    }
+}
+
+// a window that already started can still be saved, one that is over can't
+fn check_pickup_window(start: &DateTime<FixedOffset>, end: &DateTime<FixedOffset>) -> Result<(), ApiError>{
+    if end <= start {
+        return Err(ApiError::Unprocessable(format!("pickup_end must be after pickup_start")));
+    }
+    if *end <= Utc::now() {
+        return Err(ApiError::Unprocessable(format!("pickup window is in the past")));
+    }
+    Ok(())
+}
+
+fn check_quantity(quantity: Option<i32>, max_per_user: Option<i32>) -> Result<(), ApiError>{
+    if quantity.is_some_and(|q| q < 1) {
+        return Err(ApiError::Unprocessable(format!("quantity must be at least 1")));
+    }
+    if max_per_user.is_some_and(|m| m < 1) {
+        return Err(ApiError::Unprocessable(format!("max_per_user must be at least 1")));
+    }
+    Ok(())
+}
+
+fn check_best_before(best_before: &Option<DateTime<FixedOffset>>) -> Result<(), ApiError>{
+    match best_before {
+        Some(best_before) if *best_before <= Utc::now() => Err(ApiError::Unprocessable(format!("best_before must be in the future"))),
+        _ => Ok(())
+    }
+}
+
+// fills in coordinates from the address when the client didn't send any.
+// not finding the address is reported back, the donation is saved either way
+fn locate(geocoder: &dyn Geocoder, address: &str, latitude: &mut Option<f64>, longitude: &mut Option<f64>) -> GeocodeOutcome{
+    if latitude.is_some() && longitude.is_some() {
+        return GeocodeOutcome::Provided;
+    }
+    match geocoder.geocode(address) {
+        Ok((coordinates, matched)) => {
+            *latitude = Some(coordinates.latitude);
+            *longitude = Some(coordinates.longitude);
+            GeocodeOutcome::Found { matched }
+        }
+        Err(reason) => GeocodeOutcome::Failed { reason }
     }
 }
 
 #[post("/foods")] //tested
-async fn add_food(pool: web::Data<MySqlPool>, food: web::Json<FoodDetail>) -> impl Responder{
-    let food_data = food.into_inner();
+async fn add_food(pool: web::Data<MySqlPool>, auth: AuthUser, geocoder: web::Data<dyn Geocoder>, feed: web::Data<FeedBroadcaster>, food: web::Json<FoodDetail>) -> impl Responder{
+    let mut food_data = food.into_inner();
+    food_data.user_id = auth.user_id;
+    if let Err(err) = check_pickup_window(&food_data.pickup_start, &food_data.pickup_end) {
+        return failure(err);
+    }
+    if let Err(err) = check_best_before(&food_data.best_before) {
+        return failure(err);
+    }
+    if let Err(err) = check_quantity(food_data.quantity, food_data.max_per_user) {
+        return failure(err);
+    }
+    if let Err(err) = check_coordinates(food_data.latitude, food_data.longitude) {
+        return failure(err);
+    }
+    if let Err(err) = check_media(&pool, auth.user_id, &food_data.image_key).await {
+        return failure(err);
+    }
+    food_data.pickup_address = normalize_address(&food_data.pickup_address);
+    let geocoding = locate(geocoder.get_ref(), &food_data.pickup_address, &mut food_data.latitude, &mut food_data.longitude);
     match insert_food(&pool, &food_data).await {
         Ok(id) => {
                 if let Err(err) = increment_user_food_count(&pool, food_data.user_id).await {
-                    return failure(format!("There was an error: {}", err));
+                    return failure(ApiError::db("There was an error", err));
                 }
                 // println!("aggiunto cibo");
-                success("food inserted successfully", id)
+                publish_food(&pool, &feed, FeedEventKind::Created, id as i32).await;
+                success("food inserted successfully", NewFood { id, geocoding })
         }
-        Err(err) => failure(format!("There was an error: {}", err))
+        Err(err) => failure(ApiError::db("There was an error", err))
     } 
 }
 
@@ -49,39 +159,177 @@ async fn add_user(pool: web::Data<MySqlPool>, user_details: web::Json<NewUserDet
             if !exits {
                 match create_new_user(&pool, user_data).await {
                     Ok(user_id) => success("user added successfully", user_id),
-                    Err(err) => failure(format!("There was an error {}", err))
+                    Err(err) => failure(ApiError::db("There was an error", err))
                 }
             }else{
-                failure(format!("email already exists"))
+                failure(ApiError::Conflict(format!("email already exists")))
             }
         }
-        Err(err) => failure(format!("There was an error {}", err))
+        Err(err) => failure(ApiError::db("There was an error", err))
     }
     
 }
 
 #[delete("/foods/{food_id}")] // tested
-async fn delete_food_handler(pool: web::Data<MySqlPool>, path: web::Path<FoodId>) -> impl Responder{
+async fn delete_food_handler(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<FoodId>) -> impl Responder{
     let food_id = path.into_inner();
+    if let Err(err) = authorize(&pool, &auth, Owned::Food(food_id.food_id)).await {
+        return failure(err);
+    }
+    // taken before the row goes, subscribers still need to know where it was to drop it
+    let deleted = snapshot(&pool, food_id.food_id).await;
     match delete_food(&pool, food_id.food_id).await {
-        Ok(_) => success("Food deleted", None::<()>),
-        Err(err) => failure(format!("There was an error: {}", err))
+        Ok(_) => {
+            if let Some(food) = deleted {
+                feed.publish(FeedEventKind::Deleted, food).await;
+            }
+            success("Food deleted", None::<()>)
+        }
+        Err(err) => failure(ApiError::db("There was an error", err))
     }
 }
 
 #[post("/login")] // tested
-async fn login_user_handler(pool: web::Data<MySqlPool>, user: web::Json<LoginDetail>) -> impl Responder{
+async fn login_user_handler(pool: web::Data<MySqlPool>, throttle: web::Data<LoginThrottle>, req: HttpRequest, user: web::Json<LoginDetail>) -> impl Responder{
     let user_pass = user.into_inner();
+    let account = account_key(&user_pass.email);
+    let ip = ip_key(&req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default());
+    if let Some(wait) = throttle.retry_after(&[&account, &ip]) {
+        return failure(ApiError::TooManyRequests(format!("too many failed attempts, try again in {} seconds", wait.as_secs() + 1)));
+    }
     match login_user(&pool, &user_pass).await {
         Ok(Some(user_from_db)) => {
             if compare_password(&user_pass.password_hash, &user_from_db.password_hash) {
-                success("login successfully", user_from_db)
+                throttle.record_success(&account);
+                let user_id = user_from_db.id.unwrap_or_default();
+                let refresh_token = generate_token();
+                match create_session(&pool, user_id, &hash_token(&refresh_token)).await {
+                    Ok(session_id) => {
+                        let tokens = SessionTokens {
+                            access_token: issue_access_token(user_id, session_id),
+                            refresh_token,
+                            token_type: "Bearer".to_string(),
+                            expires_in: ACCESS_TOKEN_TTL_SECS
+                        };
+                        success("login successfully", LoginResponse { user: user_from_db, tokens })
+                    }
+                    Err(err) => failure(ApiError::db("There was an error creating session", err))
+                }
             }else{
-                failure(format!("incorrect password"))
+                failed_login(&throttle, &account, &ip, Some(user_pass.email.as_str())).await
             }
         }
-        Ok(None) => failure(format!("incorrect password")),
-        Err(err) => failure(format!("There was an error: {}", err))
+        Ok(None) => {
+            // same hashing work as a known email, so the response time doesn't give away which emails have accounts
+            compare_dummy_password(&user_pass.password_hash);
+            failed_login(&throttle, &account, &ip, None).await
+        }
+        Err(err) => failure(ApiError::db("There was an error", err))
+    }
+}
+
+// counts the failure against both the account and the address, the owner hears about it when the account gets locked
+async fn failed_login(throttle: &LoginThrottle, account: &str, ip: &str, user_mail: Option<&str>) -> HttpResponse{
+    throttle.record_failure(ip, MAX_IP_FAILURES);
+    if throttle.record_failure(account, MAX_ACCOUNT_FAILURES) {
+        if let Some(user_mail) = user_mail {
+            // the caller shouldn't wait on the mail server to hear their password was wrong
+            let user_mail = user_mail.to_string();
+            tokio::spawn(async move {
+                if let Err(err) = send_lockout_mail(&user_mail).await {
+                    log::warn!("couldn't send lockout mail: {}", err);
+                }
+            });
+        }
+    }
+    failure(ApiError::Unauthorized(format!("incorrect password")))
+}
+
+#[get("/admin/lockouts")]
+async fn get_lockouts(pool: web::Data<MySqlPool>, auth: AuthUser, throttle: web::Data<LoginThrottle>) -> impl Responder{
+    if let Err(err) = require_admin(&pool, &auth).await {
+        return failure(err);
+    }
+    success("successfull", throttle.lockouts())
+}
+
+#[delete("/admin/lockouts/{key}")]
+async fn clear_lockout(pool: web::Data<MySqlPool>, auth: AuthUser, throttle: web::Data<LoginThrottle>, path: web::Path<String>) -> impl Responder{
+    if let Err(err) = require_admin(&pool, &auth).await {
+        return failure(err);
+    }
+    throttle.clear(&path.into_inner());
+    success("lockout cleared", None::<()>)
+}
+
+#[post("/logout")]
+async fn logout_user(pool: web::Data<MySqlPool>, auth: AuthUser) -> impl Responder{
+    match revoke_session(&pool, auth.session_id).await {
+        Ok(_) => success("logged out", None::<()>),
+        Err(err) => failure(ApiError::db("There was an error", err))
+    }
+}
+
+#[post("/token/refresh")]
+async fn refresh_session(pool: web::Data<MySqlPool>, payload: web::Json<RefreshPayload>) -> impl Responder{
+    let new_refresh_token = generate_token();
+    match rotate_session(&pool, &hash_token(&payload.refresh_token), &hash_token(&new_refresh_token)).await {
+        Ok(Some((session_id, user_id))) => {
+            let tokens = SessionTokens {
+                access_token: issue_access_token(user_id, session_id),
+                refresh_token: new_refresh_token,
+                token_type: "Bearer".to_string(),
+                expires_in: ACCESS_TOKEN_TTL_SECS
+            };
+            success("token refreshed", tokens)
+        }
+        Ok(None) => failure(ApiError::Unauthorized(format!("invalid refresh token"))),
+        Err(err) => failure(ApiError::db("There was an error", err))
+    }
+}
+
+#[post("/password/forgot")]
+async fn forgot_password(pool: web::Data<MySqlPool>, limiter: web::Data<RateLimiter>, req: HttpRequest, payload: web::Json<ForgotPasswordPayload>) -> impl Responder{
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    if let Err(wait) = limiter.check(&format!("password-reset:{}", ip)) {
+        return failure(ApiError::TooManyRequests(format!("too many requests, try again in {} seconds", wait.as_secs() + 1)));
+    }
+    // the lookup and the mail happen after the answer has gone out, so neither the answer nor how long
+    // it takes says whether the email belongs to an account
+    let pool = pool.get_ref().clone();
+    let email = payload.into_inner().email;
+    tokio::spawn(async move {
+        if let Err(err) = send_password_reset(&pool, &email).await {
+            log::warn!("couldn't send password reset: {}", err);
+        }
+    });
+    success("if the email belongs to an account a reset token has been sent", None::<()>)
+}
+
+// unknown emails and accounts still in their cooldown are skipped quietly
+async fn send_password_reset(pool: &MySqlPool, email: &str) -> Result<(), Box<dyn std::error::Error>>{
+    let user_id = match get_active_user_id(pool, email).await? {
+        Some(user_id) => user_id,
+        None => return Ok(())
+    };
+    if seconds_since_last_reset(pool, user_id).await?.is_some_and(|elapsed| elapsed < PASSWORD_RESET_COOLDOWN_SECS) {
+        return Ok(());
+    }
+    let token = generate_token();
+    create_password_reset(pool, user_id, &hash_token(&token)).await?;
+    send_reset_mail(email, &token).await
+}
+
+#[post("/password/reset")]
+async fn reset_user_password(pool: web::Data<MySqlPool>, payload: web::Json<ResetPasswordPayload>) -> impl Responder{
+    let payload = payload.into_inner();
+    if payload.new_password.chars().count() < MIN_PASSWORD_LENGTH {
+        return failure(ApiError::Unprocessable(format!("password must be at least {} characters", MIN_PASSWORD_LENGTH)));
+    }
+    match reset_password(&pool, &hash_token(&payload.token), payload.new_password).await {
+        Ok(true) => success("password changed, please log in again", None::<()>),
+        Ok(false) => failure(ApiError::BadRequest(format!("invalid or expired token"))),
+        Err(err) => failure(ApiError::db("there was an error resetting password", err))
     }
 }
 
@@ -90,101 +338,207 @@ async fn get_user_profile_details(pool: web::Data<MySqlPool>, path: web::Path<i3
     let user_id = path.into_inner();
     match get_user_profile(&pool, user_id).await {
         Ok(user_details) => success("successful", user_details),
-        Err(err) => failure(format!("There was an error getting user details: {}", err))
+        Err(err) => failure(ApiError::db("There was an error getting user details", err))
     }
 }
 
 #[get("/foods/{id}")] //tested
-async fn get_food_profile_details(pool: web::Data<MySqlPool>, path: web::Path<i32>) -> impl Responder{
+async fn get_food_profile_details(pool: web::Data<MySqlPool>, auth: Option<AuthUser>, path: web::Path<i32>) -> impl Responder{
     let food_id = path.into_inner();
-    match get_food_detail(&pool, food_id).await {
+    match get_food_profile(&pool, food_id, auth.map(|a| a.user_id)).await {
         Ok(food_details) => success("successfull", food_details),
-        Err(err) => failure(format!("There was an error getting user details: {}", err))
+        Err(err) => failure(ApiError::db("There was an error getting food details", err))
     }
 }
 
 #[patch("/users/{user_id}/picture")] // tested
-async  fn edit_profile_pic(pool: web::Data<MySqlPool>, path: web::Path<i32>, payload: web::Json<PicturePayload>) -> impl Responder{ 
+async  fn edit_profile_pic(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>, payload: web::Json<PicturePayload>) -> impl Responder{ 
     let user_id = path.into_inner();
-    let profile_image = payload.profile_image.clone();
-    let user_pic = PictureDetails { user_id, profile_image };
+    if let Err(err) = authorize(&pool, &auth, Owned::User(user_id)).await {
+        return failure(err);
+    }
+    if let Err(err) = check_media(&pool, auth.user_id, &payload.profile_image_key).await {
+        return failure(err);
+    }
+    let profile_image_key = payload.profile_image_key.clone();
+    let user_pic = PictureDetails { user_id, profile_image_key };
     match edit_profile_picture(&pool, &user_pic).await {
         Ok(_) => success("picture added", None::<()>),
-        Err(err) => failure(format!("There was an error: {}", err))
+        Err(err) => failure(ApiError::db("There was an error", err))
     }
 }
 
-#[post("/users/{user_id}/verify")] // tested
-async fn verify_code(pool: web::Data<MySqlPool>, path: web::Path<i32>, code: web::Json<UserCodeDetails>) -> impl Responder{
-    let user_id = path.into_inner();
-    let details = UserCodeDetails{
-        user_code: code.user_code.clone(),
-        user_id,
-        user_email: code.user_email.clone()
-    };
-    match verify_user_code(&pool, &details).await {
-        Ok(exist) => {
-            if exist {
-                match update_verified(&pool, &details.user_email).await {
-                    Ok(_) => {
-                        match delete_verification_code(&pool, &details.user_email).await {
-                            Ok(_) => success("email verified", None::<()>),
-                            Err(err) => failure(format!("there was error: {}", err))
-                        }
-                    }
-                    Err(err) => failure(format!("an errror occured: {}", err))
-                }
-            }else{
-                failure(format!("wrong code"))
+#[post("/media")]
+async fn upload_media(pool: web::Data<MySqlPool>, auth: AuthUser, store: web::Data<dyn MediaStore>, mut payload: Multipart) -> impl Responder{
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(field) => field,
+            Err(err) => return failure(ApiError::BadRequest(format!("invalid upload: {}", err)))
+        };
+        if field.name() != Some("file") {
+            continue;
+        }
+        let declared = field.content_type().map(|mime| mime.essence_str().to_string());
+        if !declared.as_deref().is_some_and(|declared| ACCEPTED_CONTENT_TYPES.contains(&declared)) {
+            return failure(ApiError::Unprocessable(format!("only JPEG, PNG and WebP images are accepted")));
+        }
+
+        let max_bytes = max_upload_bytes();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => return failure(ApiError::BadRequest(format!("invalid upload: {}", err)))
+            };
+            if bytes.len() + chunk.len() > max_bytes {
+                return failure(ApiError::Unprocessable(format!("file is larger than {} bytes", max_bytes)));
             }
+            bytes.extend_from_slice(&chunk);
         }
-        Err(err) => failure(format!("there was err: {}", err))
+        // what the client claims is only a first filter, the bytes have to agree
+        if sniff_content_type(&bytes) != declared.as_deref() {
+            return failure(ApiError::Unprocessable(format!("the file is not the image type it was sent as")));
+        }
+
+        return match save_image(&pool, &store.into_inner(), auth.user_id, bytes).await {
+            Ok(uploaded) => success("file uploaded", uploaded),
+            Err(err) => failure(err)
+        };
+    }
+    failure(ApiError::BadRequest(format!("expected a multipart field named file")))
+}
+
+#[get("/media/{key}")]
+async fn get_media(pool: web::Data<MySqlPool>, store: web::Data<dyn MediaStore>, req: HttpRequest, path: web::Path<String>) -> impl Responder{
+    let key = path.into_inner();
+    if !valid_key(&key) {
+        return failure(ApiError::NotFound(format!("media not found")));
+    }
+    let content_type = match get_media_content_type(&pool, &key).await {
+        Ok(Some(content_type)) => content_type,
+        Ok(None) => return failure(ApiError::NotFound(format!("media not found"))),
+        Err(err) => return failure(ApiError::db("there was an error", err))
+    };
+
+    let etag = format!("\"{}\"", key);
+    let cached = req.headers().get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) == Some(etag.as_str());
+    if cached {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+            .finish();
+    }
+
+    let reader = store.clone().into_inner();
+    match web::block(move || reader.get(&key)).await {
+        Ok(Ok(bytes)) => HttpResponse::Ok()
+            .content_type(content_type)
+            // browsers go by the content type above and never guess from the bytes
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+            .body(bytes),
+        Ok(Err(err)) => failure(ApiError::NotFound(format!("media not found: {}", err))),
+        Err(err) => failure(ApiError::Internal(format!("couldn't read file: {}", err)))
+    }
+}
+
+// images are uploaded to /media first, anything pointing at one has to use a key that exists
+async fn check_media(pool: &MySqlPool, user_id: i32, key: &Option<String>) -> Result<(), ApiError>{
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(())
+    };
+    // keys show up in public urls, knowing one doesn't make the picture yours. uploading the same file does
+    match has_uploaded_media(pool, key, user_id).await {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(err) => return Err(ApiError::db("there was an error checking the image", err))
+    }
+    match get_media_content_type(pool, key).await {
+        Ok(Some(_)) => Err(ApiError::Forbidden(format!("you can only use images you uploaded"))),
+        Ok(None) => Err(ApiError::Unprocessable(format!("unknown image key, upload the image to /media first"))),
+        Err(err) => Err(ApiError::db("there was an error checking the image", err))
+    }
+}
+
+#[post("/users/{user_id}/verify")] // tested
+async fn verify_code(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>, code: web::Json<UserCodeDetails>) -> impl Responder{
+    let user_id = path.into_inner();
+    // otherwise anyone could use up someone else's attempts
+    if let Err(err) = authorize(&pool, &auth, Owned::User(user_id)).await {
+        return failure(err);
+    }
+    let code_hash = hash_verification_code(user_id, code.user_code.trim());
+    match check_verification_code(&pool, user_id, &code_hash).await {
+        Ok(CodeCheck::Verified) => success("email verified", None::<()>),
+        Ok(CodeCheck::Wrong { attempts_left }) => failure(ApiError::BadRequest(format!("wrong code, {} attempts left", attempts_left))),
+        Ok(CodeCheck::Locked) => failure(ApiError::TooManyRequests(format!("too many wrong attempts, try again in an hour"))),
+        Ok(CodeCheck::Expired) => failure(ApiError::BadRequest(format!("code expired, request a new code"))),
+        Ok(CodeCheck::Missing) => failure(ApiError::NotFound(format!("no verification code pending for this user"))),
+        Err(err) => failure(ApiError::db("there was an error checking the code", err))
     }
 }
 
 
 #[post("/users/{user_id}/mail")] // tested
-async fn send_verify_mail(pool: web::Data<MySqlPool>, path: web::Path<i32>) -> impl Responder{
+async fn send_verify_mail(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>) -> impl Responder{
     let code = generate_code();
 
     let user_id = path.into_inner();
+    if let Err(err) = authorize(&pool, &auth, Owned::User(user_id)).await {
+        return failure(err);
+    }
+    match seconds_since_last_code(&pool, user_id).await {
+        Ok(Some(elapsed)) if elapsed < VERIFY_CODE_RESEND_COOLDOWN_SECS => {
+            return failure(ApiError::TooManyRequests(format!("wait {} seconds before asking for a new code", VERIFY_CODE_RESEND_COOLDOWN_SECS - elapsed)))
+        }
+        Ok(_) => {}
+        Err(err) => return failure(ApiError::db("there was an error checking previous codes", err))
+    }
     match get_user_email(&pool, &user_id).await {
         Ok(user_mail) =>{
-            match add_user_code(&pool, code.clone(), user_id).await {
+            match store_verification_code(&pool, user_id, &hash_verification_code(user_id, &code)).await {
                 Ok(_) => {
                     match send_mail(&user_mail, &code).await {
                         Ok(_) => success("email sent successfully", None::<()>), 
-                        Err(err) => failure(format!("there was an error qui: {}", err))
+                        Err(err) => failure(ApiError::Internal(format!("there was an error sending mail: {}", err)))
                     }   
                 }
                 
-                Err(err) => failure(format!("there was an error qui: {}", err))
+                Err(err) => failure(ApiError::db("there was an error saving the code", err))
             }
         }
-        Err(err) => failure(format!("there was error verifying user email: {}", err))
+        Err(err) => failure(ApiError::db("there was error verifying user email", err))
     } 
 }
 
 #[delete("/users/{id}/profile")] //tested
-async fn delete_user(pool: web::Data<MySqlPool>, path: web::Path<i32>, user_details: web::Json<MajesticRes>) -> impl Responder{
+async fn delete_user(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>, user_details: web::Json<MajesticRes>) -> impl Responder{
     let id = path.into_inner();
+    if let Err(err) = authorize(&pool, &auth, Owned::User(id)).await {
+        return failure(err);
+    }
     let user_mail = user_details.user_email.clone();
     match delete_user_account(&pool, id, &user_mail).await {
         Ok(_) => {
             match send_goodbye_mail(user_mail).await {
                 Ok(_) => success("user deleted successfully", None::<()>),
-                Err(err) => failure(format!("couldn't send mail: {}", err))
+                Err(err) => failure(ApiError::Internal(format!("couldn't send mail: {}", err)))
             }
         }
-        Err(err) => failure(format!("there was an error: {}", err))
+        Err(err) => failure(ApiError::db("there was an error", err))
     }
 }
 
 
 #[patch("/users/{id}/profile")] // tested
-async fn edit_profile(pool: web::Data<MySqlPool>, path: web::Path<i32>, user_edit_details: web::Json<EditUserDetails>) -> impl Responder {
+async fn edit_profile(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>, user_edit_details: web::Json<EditUserDetails>) -> impl Responder {
     
     let user_id = path.into_inner();
+    if let Err(err) = authorize(&pool, &auth, Owned::User(user_id)).await {
+        return failure(err);
+    }
     let user_edit = EditUserDetails {
         user_id: user_id,
         first_name: user_edit_details.first_name.clone(),
@@ -199,46 +553,69 @@ async fn edit_profile(pool: web::Data<MySqlPool>, path: web::Path<i32>, user_edi
                     if !is_same {
                         match change_email_verified(&pool, &user_edit_details.email).await {
                             Ok(_) => success("successful", user_edit_details),
-                            Err(err) => failure(format!("there was an error {}", err))
+                            Err(err) => failure(ApiError::db("there was an error", err))
                         }
                     }else{
                         return success("successful", user_edit_details)
                     }
                 }
-                Err(err) => failure(format!("there was an error: {}", err))
+                Err(err) => failure(ApiError::db("there was an error", err))
             }
         }
-        Err(err) => failure(format!("there was an error: {}", err))
+        Err(err) => failure(ApiError::db("there was an error", err))
     }
    
 }
 
 #[get("/users/{id}/donations")] // tested
-async fn get_donations(pool: web::Data<MySqlPool>, path: web::Path<i32>) -> impl Responder {
+async fn get_donations(pool: web::Data<MySqlPool>, path: web::Path<i32>, query: web::Query<FoodQuery>) -> impl Responder {
     let user_id = path.into_inner();
-    match get_all_donations(&pool, user_id).await {
+    match get_all_donations(&pool, user_id, query.into_inner()).await {
         Ok(all_donations) => success("successfull", all_donations),
-        Err(err) => failure(format!("there was an error: {}", err))
+        Err(err) => failure(err)
     }
 }
 
 #[get("/users/{id}/reservations")] // tested
-async fn get_reserves(pool: web::Data<MySqlPool>, path: web::Path<i32>) -> impl Responder{
+async fn get_reserves(pool: web::Data<MySqlPool>, path: web::Path<i32>, query: web::Query<PageQuery>) -> impl Responder{
     let user_id = path.into_inner();
-    match get_user_reservations(&pool, user_id).await {
+    match get_user_reservations(&pool, user_id, &query).await {
        Ok(all_reserves) =>{
             success("successfull", all_reserves)
        }
-       Err(err) => failure(format!("error getting all reserves: {}", err)) 
+       Err(err) => failure(err) 
     }
 }
 
 #[patch("/donations")] // tested
-async fn edit_donation(pool: web::Data<MySqlPool>, food_edit_details: web::Json<FoodDetail2>) -> impl Responder {
-    let food_edit_details = food_edit_details.into_inner();
+async fn edit_donation(pool: web::Data<MySqlPool>, auth: AuthUser, geocoder: web::Data<dyn Geocoder>, feed: web::Data<FeedBroadcaster>, food_edit_details: web::Json<FoodDetail2>) -> impl Responder {
+    let mut food_edit_details = food_edit_details.into_inner();
+    if let Err(err) = authorize(&pool, &auth, Owned::Food(food_edit_details.food_id)).await {
+        return failure(err);
+    }
+    if let Err(err) = check_pickup_window(&food_edit_details.pickup_start, &food_edit_details.pickup_end) {
+        return failure(err);
+    }
+    if let Err(err) = check_best_before(&food_edit_details.best_before) {
+        return failure(err);
+    }
+    if let Err(err) = check_quantity(food_edit_details.quantity, food_edit_details.max_per_user) {
+        return failure(err);
+    }
+    if let Err(err) = check_coordinates(food_edit_details.latitude, food_edit_details.longitude) {
+        return failure(err);
+    }
+    if let Err(err) = check_media(&pool, auth.user_id, &food_edit_details.image_key).await {
+        return failure(err);
+    }
+    food_edit_details.pickup_address = normalize_address(&food_edit_details.pickup_address);
+    let geocoding = locate(geocoder.get_ref(), &food_edit_details.pickup_address, &mut food_edit_details.latitude, &mut food_edit_details.longitude);
     match update_donation(&pool, &food_edit_details).await {
-        Ok(_) => return success("successfull", food_edit_details),
-        Err(err) => failure(format!("there was an error: {}", err))
+        Ok(_) => {
+            publish_food(&pool, &feed, FeedEventKind::Updated, food_edit_details.food_id).await;
+            success("successfull", EditedFood { food: food_edit_details, geocoding })
+        }
+        Err(err) => failure(err)
     }
 }
 
@@ -247,49 +624,341 @@ async fn get_user_active_donations(pool: web::Data<MySqlPool>, path: web::Path<i
     let user_id = path.into_inner();
     match get_active_donation(&pool, user_id).await {
         Ok(all_donations) => success("successfull", all_donations),
-        Err(err) => failure(format!("there was an error getting user active donations: {}", err))
+        Err(err) => failure(ApiError::db("there was an error getting user active donations", err))
     }
 }
 
 #[post("/users/{id}/reserve")] // tested
-async fn make_user_reserve(pool: web::Data<MySqlPool>, path: web::Path<i32>, reserve_details: web::Json<ReserveDetails>) ->impl Responder{
+async fn make_user_reserve(pool: web::Data<MySqlPool>, auth: AuthUser, policy: web::Data<ReservationPolicy>, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>, reserve_details: web::Json<ReservePayload>) ->impl Responder{
     let id = path.into_inner();
-    match check_if_user_has_reserve(&pool, id.clone()).await {
-        Ok(has) => {
-            if has {
-                return failure(format!("already has a reservation"))
+    if let Err(err) = authorize(&pool, &auth, Owned::User(id)).await {
+        return failure(err);
+    }
+    let quantity = reserve_details.quantity.unwrap_or(1);
+    if quantity < 1 {
+        return failure(ApiError::Unprocessable(format!("quantity must be at least 1")));
+    }
+    match create_reservation(&pool, &policy, id, reserve_details.food_id, quantity).await {
+        Ok(reservation) => {
+            // a request waiting on the donor takes nothing off the food, so there is nothing to tell
+            if reservation.status.as_deref() != Some(ReservationStatus::Pending.as_str()) {
+                publish_food(&pool, &feed, FeedEventKind::Reserved, reserve_details.food_id).await;
             }
-                match make_reserve(&pool, reserve_details.into_inner()).await {
-                    Ok(_) =>{
-                        match mark_user_reserve(&pool, id).await {
-                            Ok(id) => {
-                                match get_reservation_details(&pool, id).await {
-                                    Ok(reserve_details) => {
-                                        return success("successfull", reserve_details)
-                                    }
-                                    Err(err) => failure(format!("there was an error getting reservation {}", err))
-                                }
-                            }
-                            Err(err) => failure(format!("error marking reservation but reservation made: {}", err))
-                        }
-                    }
-                    Err(err) => failure(format!("error making reserve: {}", err))
+            success("successfull", reservation)
+        }
+        Err(err) => failure(err)
+    }
+}
+
+#[post("/foods/{id}/waitlist")]
+async fn join_food_waitlist(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>, payload: web::Json<WaitlistPayload>) -> impl Responder{
+    let quantity = payload.quantity.unwrap_or(1);
+    if quantity < 1 {
+        return failure(ApiError::Unprocessable(format!("quantity must be at least 1")));
+    }
+    match join_waitlist(&pool, auth.user_id, path.into_inner(), quantity).await {
+        Ok(spot) => success("added to the waitlist", spot),
+        Err(err) => failure(err)
+    }
+}
+
+#[delete("/foods/{id}/waitlist")]
+async fn leave_food_waitlist(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>) -> impl Responder{
+    let food_id = path.into_inner();
+    match leave_waitlist(&pool, auth.user_id, food_id).await {
+        Ok(was_offered) => {
+            if was_offered {
+                offer_next(&pool, food_id).await;
+                publish_food(&pool, &feed, FeedEventKind::Updated, food_id).await;
+            }
+            success("removed from the waitlist", food_id)
+        }
+        Err(err) => failure(err)
+    }
+}
+
+// turns the caller's offer into a reservation for the portions they queued for
+#[post("/foods/{id}/waitlist/accept")]
+async fn accept_waitlist_offer(pool: web::Data<MySqlPool>, auth: AuthUser, policy: web::Data<ReservationPolicy>, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>) -> impl Responder{
+    let food_id = path.into_inner();
+    let quantity = match get_waitlist_offer(&pool, auth.user_id, food_id).await {
+        Ok(Some(quantity)) => quantity,
+        Ok(None) => return failure(ApiError::NotFound(format!("no open offer for you on this food"))),
+        Err(err) => return failure(ApiError::db("there was an error", err))
+    };
+    match create_reservation(&pool, &policy, auth.user_id, food_id, quantity).await {
+        Ok(reservation) => {
+            // portions may be left over for the next in line
+            offer_next(&pool, food_id).await;
+            // a request waiting on the donor gave the offered portions back instead of taking them
+            let kind = if reservation.status.as_deref() == Some(ReservationStatus::Pending.as_str()) { FeedEventKind::Updated } else { FeedEventKind::Reserved };
+            publish_food(&pool, &feed, kind, food_id).await;
+            success("successfull", reservation)
+        }
+        Err(err) => failure(err)
+    }
+}
+
+#[post("/reservations/{id}/confirm")]
+async fn confirm_reservation(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>) -> impl Responder{
+    accept_and_settle(&pool, &feed, &auth, path.into_inner()).await
+}
+
+#[get("/foods/{id}/requests")]
+async fn get_food_requests(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>) -> impl Responder{
+    let food_id = path.into_inner();
+    if let Err(err) = authorize(&pool, &auth, Owned::Food(food_id)).await {
+        return failure(err);
+    }
+    match get_pending_requests(&pool, food_id).await {
+        Ok(requests) => success("successfull", requests),
+        Err(err) => failure(ApiError::db("there was an error getting requests", err))
+    }
+}
+
+#[post("/reservations/{id}/accept")]
+async fn accept_request(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>) -> impl Responder{
+    accept_and_settle(&pool, &feed, &auth, path.into_inner()).await
+}
+
+#[post("/reservations/{id}/decline")]
+async fn decline_request(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>) -> impl Responder{
+    let reservation_id = path.into_inner();
+    match transition_reservation(&pool, reservation_id, Some(auth.user_id), ReservationStatus::Declined, None).await {
+        Ok(reservation) => {
+            notify_declined(&pool, reservation_id, true);
+            success("request declined", reservation)
+        }
+        Err(err) => failure(err)
+    }
+}
+
+// once the donor accepts someone, requests the rest of the food can't cover are declined for them
+async fn accept_and_settle(pool: &MySqlPool, feed: &FeedBroadcaster, auth: &AuthUser, reservation_id: i32) -> HttpResponse{
+    let reservation = match transition_reservation(pool, reservation_id, Some(auth.user_id), ReservationStatus::Confirmed, None).await {
+        Ok(reservation) => reservation,
+        Err(err) => return failure(err)
+    };
+    match unfit_requests(pool, reservation.food_id).await {
+        Ok(unfit) => {
+            for request_id in unfit {
+                match transition_reservation(pool, request_id, None, ReservationStatus::Declined, None).await {
+                    Ok(_) => notify_declined(pool, request_id, false),
+                    Err(err) => log::error!("couldn't decline request {}: {}", request_id, err)
+                }
+            }
+        }
+        Err(err) => log::error!("couldn't load remaining requests: {}", err)
+    }
+    publish_food(pool, feed, FeedEventKind::Reserved, reservation.food_id).await;
+    success("successfull", reservation)
+}
+
+// sent in the background, the donor shouldn't wait on the mail server for every request that got declined
+fn notify_declined(pool: &MySqlPool, reservation_id: i32, by_donor: bool){
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        match get_reservation_parties(&pool, reservation_id).await {
+            Ok(parties) => {
+                let title = parties.food_title.unwrap_or_default();
+                if let Err(err) = send_request_declined_mail(&parties.receiver_email, &title, by_donor).await {
+                    log::warn!("couldn't send declined mail: {}", err);
                 }
+            }
+            Err(err) => log::error!("couldn't load reservation parties: {}", err)
         }
-        Err(err) => failure(format!("error making reserve: {}", err))
+    });
+}
+
+// only the receiver and the donor of a reservation can read or write its thread
+async fn thread_members(pool: &MySqlPool, auth: &AuthUser, reservation_id: i32) -> Result<ReservationMembers, ApiError>{
+    let members = match get_reservation_members(pool, reservation_id).await {
+        Ok(Some(members)) => members,
+        Ok(None) => return Err(ApiError::NotFound(format!("reservation not found"))),
+        Err(err) => return Err(ApiError::db("there was an error", err))
+    };
+    if members.receiver_id != auth.user_id && members.donor_id != Some(auth.user_id) {
+        return Err(ApiError::Forbidden(format!("you are not part of this reservation")));
+    }
+    Ok(members)
+}
+
+// control characters other than line breaks are dropped. the text is stored as written,
+// escaping it is up to whatever renders it
+fn sanitize_message(body: &str) -> Result<String, ApiError>{
+    let body: String = body.trim().chars().filter(|c| !c.is_control() || *c == '\n').collect();
+    if body.is_empty() {
+        return Err(ApiError::Unprocessable(format!("message can't be empty")));
+    }
+    if body.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(ApiError::Unprocessable(format!("message can be at most {} characters", MAX_MESSAGE_LENGTH)));
     }
+    Ok(body)
 }
 
+// the thread closes by itself once the reservation is over, it stays readable
+#[post("/reservations/{id}/messages")]
+async fn post_message(pool: web::Data<MySqlPool>, auth: AuthUser, limiter: web::Data<RateLimiter>, path: web::Path<i32>, payload: web::Json<MessagePayload>) -> impl Responder{
+    let reservation_id = path.into_inner();
+    let members = match thread_members(&pool, &auth, reservation_id).await {
+        Ok(members) => members,
+        Err(err) => return failure(err)
+    };
+    if !ReservationStatus::parse(&members.status).is_some_and(|status| status.is_open()) {
+        return failure(ApiError::Conflict(format!("this conversation is closed, the reservation is {}", members.status)));
+    }
+    let body = match sanitize_message(&payload.body) {
+        Ok(body) => body,
+        Err(err) => return failure(err)
+    };
+    if let Err(wait) = limiter.check(&format!("messages:{}", auth.user_id)) {
+        return failure(ApiError::TooManyRequests(format!("you are sending messages too fast, try again in {} seconds", wait.as_secs() + 1)));
+    }
+    match insert_message(&pool, reservation_id, auth.user_id, &body).await {
+        Ok(message) => success("message sent", message),
+        Err(err) => failure(ApiError::db("there was an error sending the message", err))
+    }
+}
+
+#[get("/reservations/{id}/messages")]
+async fn list_messages(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>, query: web::Query<PageQuery>) -> impl Responder{
+    let reservation_id = path.into_inner();
+    let members = match thread_members(&pool, &auth, reservation_id).await {
+        Ok(members) => members,
+        Err(err) => return failure(err)
+    };
+    let closed = !ReservationStatus::parse(&members.status).is_some_and(|status| status.is_open());
+    match get_messages(&pool, reservation_id, auth.user_id, &query).await {
+        Ok(messages) => success("successfull", MessageThread { reservation_id, closed, messages }),
+        Err(err) => failure(err)
+    }
+}
+
+// what the receiver shows at the door, only once the donor has confirmed
+#[get("/reservations/{id}/pickup-code")]
+async fn get_pickup_code(pool: web::Data<MySqlPool>, auth: AuthUser, path: web::Path<i32>) -> impl Responder{
+    let reservation_id = path.into_inner();
+    let secret = match get_pickup_secret(&pool, reservation_id).await {
+        Ok(Some(secret)) => secret,
+        Ok(None) => return failure(ApiError::NotFound(format!("reservation not found"))),
+        Err(err) => return failure(ApiError::db("there was an error", err))
+    };
+    if secret.receiver_id != auth.user_id {
+        return failure(ApiError::Forbidden(format!("only the person who reserved can see the pickup code")));
+    }
+    match (secret.status.as_str(), secret.pickup_nonce) {
+        ("confirmed", Some(nonce)) => success("successfull", PickupCode {
+            reservation_id,
+            code: pickup_code(reservation_id, &nonce),
+            qr_payload: pickup_qr_payload(reservation_id, &nonce),
+            expires_at: secret.expires_at
+        }),
+        _ => failure(ApiError::Conflict(format!("the pickup code is only available while the reservation is confirmed")))
+    }
+}
+
+// the donor submits the code or the scanned qr payload. the status change is what makes a code single use,
+// a second submission finds the reservation already picked up
+#[post("/reservations/{id}/pickup")]
+async fn confirm_pickup(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>, payload: web::Json<PickupPayload>) -> impl Responder{
+    let reservation_id = path.into_inner();
+    let secret = match get_pickup_secret(&pool, reservation_id).await {
+        Ok(Some(secret)) => secret,
+        Ok(None) => return failure(ApiError::NotFound(format!("reservation not found"))),
+        Err(err) => return failure(ApiError::db("there was an error", err))
+    };
+    if secret.donor_id != Some(auth.user_id) {
+        return failure(ApiError::Forbidden(format!("only the donor can confirm a pickup")));
+    }
+    let nonce = match (secret.status.as_str(), secret.pickup_nonce) {
+        ("confirmed", Some(nonce)) => nonce,
+        _ => return failure(ApiError::Conflict(format!("reservation is {}, not waiting for pickup", secret.status)))
+    };
+    if secret.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return failure(ApiError::Conflict(format!("the pickup window for this reservation has closed")));
+    }
+    if !is_pickup_qr(&payload.code) {
+        match take_pickup_attempt(&pool, reservation_id).await {
+            Ok(true) => {}
+            Ok(false) => return failure(ApiError::TooManyRequests(format!("too many wrong pickup codes, scan the qr code instead"))),
+            Err(err) => return failure(ApiError::db("there was an error", err))
+        }
+    }
+    if !verify_pickup(reservation_id, &nonce, &payload.code) {
+        return failure(ApiError::Unprocessable(format!("pickup code doesn't match")));
+    }
+    change_reservation_status(&pool, &feed, &auth, reservation_id, ReservationStatus::PickedUp).await
+}
+
+#[post("/reservations/{id}/no-show")]
+async fn mark_no_show(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>) -> impl Responder{
+    change_reservation_status(&pool, &feed, &auth, path.into_inner(), ReservationStatus::NoShow).await
+}
+
+async fn change_reservation_status(pool: &MySqlPool, feed: &FeedBroadcaster, auth: &AuthUser, reservation_id: i32, next: ReservationStatus) -> HttpResponse{
+    match transition_reservation(pool, reservation_id, Some(auth.user_id), next, None).await {
+        Ok(reservation) => {
+            if next.releases_food() {
+                offer_next(pool, reservation.food_id).await;
+            }
+            publish_food(pool, feed, FeedEventKind::Updated, reservation.food_id).await;
+            success("successfull", reservation)
+        }
+        Err(err) => failure(err)
+    }
+}
+
+#[post("/reservations/{id}/cancel")]
+async fn cancel_reservation(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>, payload: web::Json<CancelPayload>) -> impl Responder{
+    cancel_and_notify(&pool, &feed, &auth, path.into_inner(), payload.into_inner().reason).await
+}
+
+// kept for older clients, cancels only the caller's open reservation on that food
 #[delete("/users/{id}/reserve")] // tested
-async fn cancel_reserve(pool: web::Data<MySqlPool>, path: web::Path<i32>, reserve_details: web::Json<ReserveDetails>) -> impl Responder{
+async fn cancel_reserve(pool: web::Data<MySqlPool>, auth: AuthUser, feed: web::Data<FeedBroadcaster>, path: web::Path<i32>, reserve_details: web::Json<ReservePayload>) -> impl Responder{
     let user_id = path.into_inner();
-    let reserve = ReserveDetails {
-        food_id: reserve_details.food_id,
-        user_id
-    };
-    match edit_reservation(&pool, reserve).await { 
-        Ok(_) => success("reservation cancelled", None::<()>),
-    Err(err) => failure(format!("there was an error: {}", err))
+    if let Err(err) = authorize(&pool, &auth, Owned::User(user_id)).await {
+        return failure(err);
+    }
+    match find_open_reservation(&pool, user_id, reserve_details.food_id).await {
+        Ok(Some(reservation_id)) => cancel_and_notify(&pool, &feed, &auth, reservation_id, None).await,
+        Ok(None) => failure(ApiError::NotFound(format!("no open reservation on this food"))),
+        Err(err) => failure(ApiError::db("there was an error", err))
+    }
+}
+
+// whoever did not cancel gets an email, a failed mail doesn't undo the cancellation
+async fn cancel_and_notify(pool: &MySqlPool, feed: &FeedBroadcaster, auth: &AuthUser, reservation_id: i32, reason: Option<String>) -> HttpResponse{
+    let reason = reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty());
+    if reason.as_ref().is_some_and(|r| r.chars().count() > MAX_CANCEL_REASON_LENGTH) {
+        return failure(ApiError::Unprocessable(format!("reason can be at most {} characters", MAX_CANCEL_REASON_LENGTH)));
+    }
+    match transition_reservation(pool, reservation_id, Some(auth.user_id), ReservationStatus::Cancelled, reason.clone()).await {
+        Ok(reservation) => {
+            offer_next(pool, reservation.food_id).await;
+            publish_food(pool, feed, FeedEventKind::Updated, reservation.food_id).await;
+            // sent in the background like the other notifications, the caller doesn't wait on the mail server
+            let pool = pool.clone();
+            let caller_id = auth.user_id;
+            tokio::spawn(async move {
+                match get_reservation_parties(&pool, reservation_id).await {
+                    Ok(parties) => {
+                        let (notify, cancelled_by) = if parties.receiver_id == caller_id {
+                            (parties.donor_email, "the person who reserved it")
+                        }else{
+                            (parties.receiver_email, "the donor")
+                        };
+                        let title = parties.food_title.unwrap_or_default();
+                        if let Err(err) = send_cancellation_mail(&notify, &title, cancelled_by, reason.as_deref()).await {
+                            log::warn!("couldn't send cancellation mail: {}", err);
+                        }
+                    }
+                    Err(err) => log::error!("couldn't load reservation parties: {}", err)
+                }
+            });
+            success("reservation cancelled", reservation)
+        }
+        Err(err) => failure(err)
     }
 }
 
@@ -297,8 +966,8 @@ async fn cancel_reserve(pool: web::Data<MySqlPool>, path: web::Path<i32>, reserv
 async fn get_user_active_reserve(pool: web::Data<MySqlPool>, path: web::Path<i32>) -> impl Responder{
     let user_id = path.into_inner();
     match get_active_reserve(&pool, user_id).await {
-        Ok(active_reserve) => success("successfull", active_reserve),
-        Err(err) => failure(format!("error getting active reservation: {}", err))
+        Ok(active_reserves) => success("successfull", active_reserves),
+        Err(err) => failure(ApiError::db("error getting active reservations", err))
     }
 }
 
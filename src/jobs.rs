@@ -0,0 +1,127 @@
+use sqlx::MySqlPool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::{clear_waitlist, drop_lapsed_offer, due_reservations, get_reservation_parties, lapsed_offers, mark_food_expired, open_reservations_for_food, spoiled_foods, transition_reservation, SpoiledFood};
+use crate::errors::ApiError;
+use crate::feed::{publish_food, FeedBroadcaster, FeedEventKind};
+use crate::functions::{send_cancellation_mail, send_food_expired_mail};
+use crate::reservation::ReservationStatus;
+use crate::waitlist::offer_next;
+
+const TICK: Duration = Duration::from_secs(60);
+const BATCH_SIZE: i64 = 100;
+const SPOILED_REASON: &str = "the food passed its best-before date";
+
+// background work that runs next to the http server, one tokio task per job
+pub fn start(pool: MySqlPool, feed: Arc<FeedBroadcaster>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            if let Err(err) = expire_reservations(&pool, &feed).await {
+                log::error!("expiring reservations failed: {}", err);
+            }
+            if let Err(err) = expire_foods(&pool, &feed).await {
+                log::error!("expiring foods failed: {}", err);
+            }
+            if let Err(err) = expire_offers(&pool, &feed).await {
+                log::error!("expiring waitlist offers failed: {}", err);
+            }
+        }
+    });
+}
+
+// reservations past their pickup window: never confirmed ones expire, confirmed ones are no-shows.
+// each row goes through the same locked transition as a user action, so when another instance
+// already handled it we just get a conflict and move on
+async fn expire_reservations(pool: &MySqlPool, feed: &FeedBroadcaster) -> Result<(), ApiError> {
+    let due = due_reservations(pool, BATCH_SIZE).await?;
+    for (reservation_id, status) in due {
+        let next = match ReservationStatus::parse(&status) {
+            Some(ReservationStatus::Confirmed) => ReservationStatus::NoShow,
+            _ => ReservationStatus::Expired,
+        };
+        match transition_reservation(pool, reservation_id, None, next, None).await {
+            Ok(reservation) => {
+                offer_next(pool, reservation.food_id).await;
+                publish_food(pool, feed, FeedEventKind::Updated, reservation.food_id).await;
+            }
+            Err(ApiError::Conflict(_)) | Err(ApiError::NotFound(_)) => {}
+            // one bad row shouldn't hold up the rest of the batch, it comes round again next tick
+            Err(err) => log::error!("couldn't expire reservation {}: {}", reservation_id, err),
+        }
+    }
+    Ok(())
+}
+
+// food past its best-before date comes off the listings and whoever was going to pick it up is told.
+// the food is delisted first, so releasing its reservations can't put it back on the shelf.
+// if a run stops halfway the food comes back from spoiled_foods already expired and the next one finishes the job
+async fn expire_foods(pool: &MySqlPool, feed: &FeedBroadcaster) -> Result<(), ApiError> {
+    for food in spoiled_foods(pool, BATCH_SIZE).await? {
+        let food_id = food.id;
+        // one food failing shouldn't keep the rest of the batch on the listings
+        if let Err(err) = expire_food(pool, feed, food).await {
+            log::error!("couldn't expire food {}: {}", food_id, err);
+        }
+    }
+    Ok(())
+}
+
+async fn expire_food(pool: &MySqlPool, feed: &FeedBroadcaster, food: SpoiledFood) -> Result<(), ApiError> {
+    let expired = food.status.as_deref() == Some("expired");
+    if !expired && !mark_food_expired(pool, food.id).await? {
+        return Ok(());
+    }
+    if let Err(err) = clear_waitlist(pool, food.id).await {
+        log::error!("couldn't clear the waitlist of food {}: {}", food.id, err);
+    }
+    let title = food.title.unwrap_or_default();
+
+    let mut cancelled = 0;
+    for reservation_id in open_reservations_for_food(pool, food.id).await? {
+        match transition_reservation(pool, reservation_id, None, ReservationStatus::Cancelled, Some(SPOILED_REASON.to_string())).await {
+            Ok(_) => cancelled += 1,
+            Err(ApiError::Conflict(_)) | Err(ApiError::NotFound(_)) => continue,
+            // left open, the food comes back next tick and this one gets another go
+            Err(err) => {
+                log::error!("couldn't cancel reservation {}: {}", reservation_id, err);
+                continue;
+            }
+        }
+        let pool = pool.clone();
+        let title = title.clone();
+        tokio::spawn(async move {
+            match get_reservation_parties(&pool, reservation_id).await {
+                Ok(parties) => {
+                    if let Err(err) = send_cancellation_mail(&parties.receiver_email, &title, "Avanzo", Some(SPOILED_REASON)).await {
+                        log::warn!("couldn't send cancellation mail: {}", err);
+                    }
+                }
+                Err(err) => log::error!("couldn't load reservation parties: {}", err)
+            }
+        });
+    }
+
+    publish_food(pool, feed, FeedEventKind::Updated, food.id).await;
+
+    let donor_email = food.donor_email;
+    tokio::spawn(async move {
+        if let Err(err) = send_food_expired_mail(&donor_email, &title, cancelled).await {
+            log::warn!("couldn't send food expired mail: {}", err);
+        }
+    });
+    Ok(())
+}
+
+// nobody answered in time, the offer moves on to the next person in line
+async fn expire_offers(pool: &MySqlPool, feed: &FeedBroadcaster) -> Result<(), ApiError> {
+    for food_id in lapsed_offers(pool, BATCH_SIZE).await? {
+        if drop_lapsed_offer(pool, food_id).await? {
+            offer_next(pool, food_id).await;
+            publish_food(pool, feed, FeedEventKind::Updated, food_id).await;
+        }
+    }
+    Ok(())
+}
@@ -3,12 +3,28 @@ use actix_web::{web, App, HttpServer};
 
 // use functions::generate_code;
 use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use dotenvy::dotenv;
-use sqlx::mysql::MySqlPoolOptions;
+use sqlx::mysql::{MySqlConnectOptions, MySqlPoolOptions};
 
+mod auth;
 mod db;
+mod errors;
+mod feed;
 mod functions;
+mod geo;
 mod handlers;
+mod jobs;
+mod media;
+mod reservation;
+mod taxonomy;
+mod throttle;
+mod waitlist;
+
+#[cfg(test)]
+mod tests;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -17,46 +33,120 @@ async fn main() -> std::io::Result<()> {
 
 async fn server() -> std::io::Result<()>{
     dotenv().ok();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    auth::load_token_secret().expect("could not load the token secret");
+    functions::prepare_dummy_password();
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    // DATETIME columns hold naive UTC, so NOW() has to be UTC too whatever the server is set to
+    let connect_options = MySqlConnectOptions::from_str(&database_url)
+                                    .expect("DATABASE_URL is not a valid url")
+                                    .timezone(Some("+00:00".to_string()));
     let pool = MySqlPoolOptions::new()
                                     .max_connections(20)
-                                    .connect(&database_url)
+                                    .connect_with(connect_options)
                                     .await
                                     .expect("could not connecty to Db");
+    let gazetteer = match env::var("GAZETTEER_PATH") {
+        Ok(path) => geo::Gazetteer::load(&path).expect("could not read gazetteer"),
+        Err(_) => geo::Gazetteer::default()
+    };
+    log::info!("Loaded {} gazetteer entries", gazetteer.len());
+    let geocoder: web::Data<dyn geo::Geocoder> = web::Data::from(Arc::new(gazetteer) as Arc<dyn geo::Geocoder>);
+
+    let media_dir = env::var("MEDIA_DIR").unwrap_or("media".to_string());
+    let store: Arc<dyn media::MediaStore> = Arc::new(media::LocalStore::new(media_dir)?);
+    if env::args().nth(1).as_deref() == Some("migrate-images") {
+        media::migrate_blobs(&pool, store).await.expect("image migration failed");
+        return Ok(());
+    }
+    let media_store: web::Data<dyn media::MediaStore> = web::Data::from(store);
+
+    // shared with the jobs, food they expire shows up in the feed too
+    let feed = Arc::new(feed::FeedBroadcaster::default());
+    jobs::start(pool.clone(), feed.clone());
+    let feed: web::Data<feed::FeedBroadcaster> = web::Data::from(feed);
     let port = 8080;
-    println!("Starting server on port {port}");
+    log::info!("Starting server on port {port}");
     let addrs = ("127.0.0.1", port);
     // let frontend_url = std::env::var("FRONTEND_URL").unwrap_or("http://localhost:3000".to_string());
     const NUM: usize = 2;
+    // built once so every worker shares the same counters
+    let login_throttle = web::Data::new(throttle::LoginThrottle::new(Box::new(throttle::MemoryStore::default())));
+    let reservation_policy = web::Data::new(reservation::ReservationPolicy::from_env());
+    let message_limiter = web::Data::new(throttle::RateLimiter::new(throttle::MAX_MESSAGES_PER_MINUTE, Duration::from_secs(60)));
     HttpServer::new(move || {
         App::new()
         .wrap(
            Cors::permissive()
         )
         .app_data(web::Data::new(pool.clone()))
-        .service(handlers::get_food_list)
-        .service(handlers::add_food)
-        .service(handlers::add_user)
-        .service(handlers::delete_food_handler)
-        .service(handlers::login_user_handler)
-        .service(handlers::verify_code)
-        .service(handlers::send_verify_mail)
-        .service(handlers::edit_profile_pic)
-        .service(handlers::delete_user)
-        .service(handlers::get_donations)
-        .service(handlers::edit_donation)
-        .service(handlers::get_user_active_donations)
-        .service(handlers::cancel_reserve)
-        .service(handlers::get_user_active_reserve)
-        .service(handlers::edit_profile)
-        .service(handlers::make_user_reserve)
-        .service(handlers::get_reserves)
-        .service(handlers::get_user_profile_details)
-        .service(handlers::get_food_profile_details)
+        .app_data(login_throttle.clone())
+        .app_data(geocoder.clone())
+        .app_data(media_store.clone())
+        .app_data(reservation_policy.clone())
+        .app_data(message_limiter.clone())
+        .app_data(feed.clone())
+        .configure(routes)
     })
     .bind(addrs)?
     .workers(NUM)
     .run()
     .await
-}
\ No newline at end of file
+}
+
+// the extractor config and every endpoint, shared by the server and the tests
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(|err, _| {
+        errors::ApiError::Unprocessable(format!("invalid request body: {}", err)).into()
+    }))
+    .app_data(web::QueryConfig::default().error_handler(|err, _| {
+        errors::ApiError::BadRequest(format!("invalid query: {}", err)).into()
+    }))
+    .app_data(web::PathConfig::default().error_handler(|err, _| {
+        errors::ApiError::BadRequest(format!("invalid path: {}", err)).into()
+    }))
+    .service(handlers::get_food_list)
+    // before /foods/{id} so "nearby" isn't taken for an id
+    .service(handlers::get_food_nearby)
+    .service(handlers::food_feed)
+    .service(handlers::add_food)
+    .service(handlers::add_user)
+    .service(handlers::delete_food_handler)
+    .service(handlers::login_user_handler)
+    .service(handlers::logout_user)
+    .service(handlers::refresh_session)
+    .service(handlers::forgot_password)
+    .service(handlers::reset_user_password)
+    .service(handlers::get_lockouts)
+    .service(handlers::clear_lockout)
+    .service(handlers::verify_code)
+    .service(handlers::send_verify_mail)
+    .service(handlers::edit_profile_pic)
+    .service(handlers::delete_user)
+    .service(handlers::get_donations)
+    .service(handlers::edit_donation)
+    .service(handlers::get_user_active_donations)
+    .service(handlers::cancel_reserve)
+    .service(handlers::get_user_active_reserve)
+    .service(handlers::edit_profile)
+    .service(handlers::make_user_reserve)
+    .service(handlers::confirm_reservation)
+    .service(handlers::confirm_pickup)
+    .service(handlers::get_pickup_code)
+    .service(handlers::post_message)
+    .service(handlers::list_messages)
+    .service(handlers::mark_no_show)
+    .service(handlers::join_food_waitlist)
+    .service(handlers::leave_food_waitlist)
+    .service(handlers::accept_waitlist_offer)
+    .service(handlers::get_food_requests)
+    .service(handlers::accept_request)
+    .service(handlers::decline_request)
+    .service(handlers::cancel_reservation)
+    .service(handlers::get_reserves)
+    .service(handlers::get_user_profile_details)
+    .service(handlers::get_food_profile_details)
+    .service(handlers::upload_media)
+    .service(handlers::get_media);
+}
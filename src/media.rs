@@ -0,0 +1,262 @@
+use actix_web::web;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dotenvy::dotenv;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use sha2::{Digest, Sha256};
+use sqlx::MySqlPool;
+use std::env;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::db::{blob_food_images, blob_profile_images, insert_media, set_food_image_key, set_profile_image_key, UploadedMedia};
+use crate::errors::ApiError;
+
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 5 * 1024 * 1024;
+const MAX_IMAGE_SIDE_PX: u32 = 10_000;
+// what the decoder may allocate, a small file can still claim a huge canvas
+const MAX_DECODE_BYTES: u64 = 128 * 1024 * 1024;
+const FULL_SIDE_PX: u32 = 1600;
+const THUMBNAIL_SIDE_PX: u32 = 320;
+const JPEG_QUALITY: u8 = 85;
+// keys are content hashes, so whatever sits behind a url never changes
+pub const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Where uploaded files live. Keys are always 64 hex characters.
+pub trait MediaStore: Send + Sync {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()>;
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(LocalStore { root })
+    }
+
+    // spread files over subfolders named after the first two characters of the key
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(&key[..2]).join(key)
+    }
+}
+
+impl MediaStore for LocalStore {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        if path.exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(path.parent().expect("media path has a parent"))?;
+        // write next to the target then rename, so readers never see half a file
+        let partial = path.with_extension("part");
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(partial, path)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.path(key))
+    }
+}
+
+pub fn media_key(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn valid_key(key: &str) -> bool {
+    key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+}
+
+pub fn media_url(key: &str) -> String {
+    format!("/media/{}", key)
+}
+
+// the only types uploads may have, anything else a browser might end up running
+pub const ACCEPTED_CONTENT_TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/webp"];
+
+pub fn sniff_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    }else if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some("image/png")
+    }else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    }else{
+        None
+    }
+}
+
+// MEDIA_MAX_BYTES overrides the default upload limit
+pub fn max_upload_bytes() -> usize {
+    dotenv().ok();
+    env::var("MEDIA_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+}
+
+pub struct ProcessedImage {
+    pub content_type: &'static str,
+    pub full: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
+fn fit(image: &DynamicImage, side: u32) -> DynamicImage {
+    if image.width() <= side && image.height() <= side {
+        return image.clone();
+    }
+    image.resize(side, side, image::imageops::FilterType::Lanczos3)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY);
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)
+        }
+        format => image.write_to(&mut Cursor::new(&mut bytes), format)
+    }.map_err(|err| format!("couldn't encode image: {}", err))?;
+    Ok(bytes)
+}
+
+// checks the bytes really are a JPEG, PNG or WebP and redraws them into a full size and a thumbnail.
+// only pixels survive the re-encode, so EXIF (and the GPS position of the donor's kitchen) is dropped
+pub fn process_image(bytes: &[u8]) -> Result<ProcessedImage, String> {
+    let content_type = sniff_content_type(bytes).ok_or_else(|| format!("only JPEG, PNG and WebP images are accepted"))?;
+    let format = match content_type {
+        "image/jpeg" => ImageFormat::Jpeg,
+        "image/png" => ImageFormat::Png,
+        _ => ImageFormat::WebP,
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE_PX);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE_PX);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(|err| format!("unreadable image: {}", err))?;
+    // phones store rotation in EXIF, apply it before EXIF goes away
+    let orientation = decoder.orientation().map_err(|err| format!("unreadable image: {}", err))?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|err| format!("unreadable image: {}", err))?;
+    image.apply_orientation(orientation);
+
+    Ok(ProcessedImage {
+        content_type,
+        full: encode(&fit(&image, FULL_SIDE_PX), format)?,
+        thumbnail: encode(&fit(&image, THUMBNAIL_SIDE_PX), format)?,
+    })
+}
+
+async fn put(store: &Arc<dyn MediaStore>, key: &str, bytes: Vec<u8>) -> Result<(), ApiError> {
+    let store = store.clone();
+    let key = key.to_string();
+    match web::block(move || store.put(&key, &bytes)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => Err(ApiError::Internal(format!("couldn't store file: {}", err))),
+        Err(err) => Err(ApiError::Internal(format!("couldn't store file: {}", err)))
+    }
+}
+
+/// Validates an uploaded image, stores its full size and thumbnail variants and records both.
+pub async fn save_image(pool: &MySqlPool, store: &Arc<dyn MediaStore>, owner_id: i32, bytes: Vec<u8>) -> Result<UploadedMedia, ApiError> {
+    let processed = match web::block(move || process_image(&bytes)).await {
+        Ok(Ok(processed)) => processed,
+        Ok(Err(reason)) => return Err(ApiError::Unprocessable(reason)),
+        Err(err) => return Err(ApiError::Internal(format!("couldn't process image: {}", err)))
+    };
+
+    let key = media_key(&processed.full);
+    let thumbnail_key = media_key(&processed.thumbnail);
+    let byte_size = processed.full.len() as i64;
+    let thumbnail_size = processed.thumbnail.len() as i64;
+    put(store, &key, processed.full).await?;
+    put(store, &thumbnail_key, processed.thumbnail).await?;
+
+    insert_media(pool, &thumbnail_key, owner_id, processed.content_type, thumbnail_size, None).await?;
+    insert_media(pool, &key, owner_id, processed.content_type, byte_size, Some(&thumbnail_key)).await?;
+
+    Ok(UploadedMedia {
+        url: media_url(&key),
+        thumbnail_url: media_url(&thumbnail_key),
+        key,
+        content_type: processed.content_type.to_string(),
+        byte_size
+    })
+}
+
+// old rows hold whatever the client sent, usually base64 text (sometimes a data: url) stored as bytes
+fn decode_legacy_blob(blob: Vec<u8>) -> Vec<u8> {
+    let text = match std::str::from_utf8(&blob) {
+        Ok(text) => text.trim(),
+        Err(_) => return blob,
+    };
+    let encoded = match text.split_once(";base64,") {
+        Some((_, encoded)) => encoded,
+        None => text,
+    };
+    STANDARD.decode(encoded).unwrap_or(blob)
+}
+
+// goes through the same pipeline as an upload. what can't be decoded is left where it is rather than
+// served as an image, None tells the caller the row was skipped
+async fn store_legacy(pool: &MySqlPool, store: &Arc<dyn MediaStore>, owner_id: i32, blob: Vec<u8>) -> Result<Option<String>, ApiError> {
+    match save_image(pool, store, owner_id, decode_legacy_blob(blob)).await {
+        Ok(saved) => Ok(Some(saved.key)),
+        Err(ApiError::Unprocessable(_)) => Ok(None),
+        Err(err) => Err(err)
+    }
+}
+
+/// One-off move of image BLOBs into the media store, run with `cargo run -- migrate-images`.
+/// Migrated rows lose their BLOB, so running it again only picks up what is left.
+/// Rows that aren't a readable image keep their BLOB and are listed in the log.
+pub async fn migrate_blobs(pool: &MySqlPool, store: Arc<dyn MediaStore>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut foods = 0;
+    let mut last_id = 0;
+    loop {
+        let batch = blob_food_images(pool, last_id, 50).await?;
+        if batch.is_empty() {
+            break;
+        }
+        for (food_id, owner_id, blob) in batch {
+            last_id = food_id;
+            match store_legacy(pool, &store, owner_id, blob).await? {
+                Some(key) => {
+                    set_food_image_key(pool, food_id, &key).await?;
+                    foods += 1;
+                }
+                None => log::warn!("food {} skipped: its image isn't a readable JPEG, PNG or WebP", food_id),
+            }
+        }
+    }
+
+    let mut users = 0;
+    let mut last_id = 0;
+    loop {
+        let batch = blob_profile_images(pool, last_id, 50).await?;
+        if batch.is_empty() {
+            break;
+        }
+        for (user_id, blob) in batch {
+            last_id = user_id;
+            match store_legacy(pool, &store, user_id, blob).await? {
+                Some(key) => {
+                    set_profile_image_key(pool, user_id, &key).await?;
+                    users += 1;
+                }
+                None => log::warn!("user {} skipped: their profile picture isn't a readable JPEG, PNG or WebP", user_id),
+            }
+        }
+    }
+
+    log::info!("migrated {} food images and {} profile pictures", foods, users);
+    Ok(())
+}
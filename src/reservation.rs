@@ -0,0 +1,171 @@
+use dotenvy::dotenv;
+use std::env;
+
+// how long a reservation holds the food before the scheduler gives up on it,
+// cut short when the pickup window closes earlier
+pub const RESERVATION_HOLD_HOURS: i32 = 24;
+
+// where a reservation is in its life. requested and confirmed are open and hold the food,
+// pending is open but waits for the donor's approval first, everything else is final
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReservationStatus {
+    Pending,
+    Requested,
+    Confirmed,
+    PickedUp,
+    Cancelled,
+    NoShow,
+    Expired,
+    Declined,
+}
+
+// who is asking for a status change
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Actor {
+    Donor,
+    Receiver,
+    System,
+}
+
+impl Actor {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Actor::Donor => "donor",
+            Actor::Receiver => "receiver",
+            Actor::System => "system",
+        }
+    }
+}
+
+impl ReservationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReservationStatus::Pending => "pending",
+            ReservationStatus::Requested => "requested",
+            ReservationStatus::Confirmed => "confirmed",
+            ReservationStatus::PickedUp => "picked_up",
+            ReservationStatus::Cancelled => "cancelled",
+            ReservationStatus::NoShow => "no_show",
+            ReservationStatus::Expired => "expired",
+            ReservationStatus::Declined => "declined",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(ReservationStatus::Pending),
+            "requested" => Some(ReservationStatus::Requested),
+            "confirmed" => Some(ReservationStatus::Confirmed),
+            "picked_up" => Some(ReservationStatus::PickedUp),
+            "cancelled" => Some(ReservationStatus::Cancelled),
+            "no_show" => Some(ReservationStatus::NoShow),
+            "expired" => Some(ReservationStatus::Expired),
+            "declined" => Some(ReservationStatus::Declined),
+            _ => None,
+        }
+    }
+
+    // open reservations keep their message thread open
+    pub fn is_open(&self) -> bool {
+        matches!(self, ReservationStatus::Pending | ReservationStatus::Requested | ReservationStatus::Confirmed)
+    }
+
+    // portions are only taken off the food once the reservation is past approval
+    pub fn holds_food(&self) -> bool {
+        matches!(self, ReservationStatus::Requested | ReservationStatus::Confirmed)
+    }
+
+    // the food goes back on the shelf when a reservation ends without a pickup
+    pub fn releases_food(&self) -> bool {
+        matches!(self, ReservationStatus::Cancelled | ReservationStatus::NoShow | ReservationStatus::Expired | ReservationStatus::Declined)
+    }
+
+    pub fn can_become(&self, next: ReservationStatus) -> bool {
+        use ReservationStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Confirmed)
+                | (Pending, Declined)
+                | (Pending, Cancelled)
+                | (Pending, Expired)
+                | (Requested, Confirmed)
+                | (Requested, Cancelled)
+                | (Requested, Expired)
+                | (Confirmed, PickedUp)
+                | (Confirmed, Cancelled)
+                | (Confirmed, NoShow)
+        )
+    }
+
+    // the donor runs the handover, either side can back out (the scheduler too, when the food spoils),
+    // only the scheduler expires things
+    pub fn allowed_for(&self, actor: Actor) -> bool {
+        use ReservationStatus::*;
+        match self {
+            Confirmed | PickedUp => matches!(actor, Actor::Donor),
+            NoShow => matches!(actor, Actor::Donor | Actor::System),
+            Cancelled => matches!(actor, Actor::Donor | Actor::Receiver | Actor::System),
+            Expired => matches!(actor, Actor::System),
+            // the scheduler declines what no longer fits once the donor accepted someone else
+            Declined => matches!(actor, Actor::Donor | Actor::System),
+            Pending | Requested => false,
+        }
+    }
+}
+
+// how much a user may have reserved at once, set from the environment at startup
+pub struct ReservationPolicy {
+    pub max_active: i64,
+    pub max_active_verified: i64,
+    pub max_active_organization: i64,
+    // a no-show blocks new reservations for this long
+    pub no_show_cooldown_hours: i64,
+}
+
+// what the policy looks at, read from the user and their reservations
+pub struct ReserverStanding {
+    pub verified: bool,
+    pub organization: bool,
+    pub active: i64,
+    pub secs_since_no_show: Option<i64>,
+}
+
+fn env_or(name: &str, default: i64) -> i64 {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+impl ReservationPolicy {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        ReservationPolicy {
+            max_active: env_or("RESERVATION_MAX_ACTIVE", 1),
+            max_active_verified: env_or("RESERVATION_MAX_ACTIVE_VERIFIED", 3),
+            max_active_organization: env_or("RESERVATION_MAX_ACTIVE_ORGANIZATION", 10),
+            no_show_cooldown_hours: env_or("RESERVATION_NO_SHOW_COOLDOWN_HOURS", 48),
+        }
+    }
+
+    pub fn limit_for(&self, standing: &ReserverStanding) -> i64 {
+        if standing.organization {
+            self.max_active_organization
+        }else if standing.verified {
+            self.max_active_verified
+        }else{
+            self.max_active
+        }
+    }
+
+    // Err carries the message shown to the user
+    pub fn check(&self, standing: &ReserverStanding) -> Result<(), String> {
+        let cooldown_secs = self.no_show_cooldown_hours * 3600;
+        if let Some(since) = standing.secs_since_no_show.filter(|since| *since < cooldown_secs) {
+            let hours_left = (cooldown_secs - since + 3599) / 3600;
+            return Err(format!("you missed a pickup recently, you can reserve again in {} hours", hours_left));
+        }
+        let limit = self.limit_for(standing);
+        if standing.active >= limit {
+            return Err(format!("you can have at most {} open reservations", limit));
+        }
+        Ok(())
+    }
+}
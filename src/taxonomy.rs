@@ -0,0 +1,158 @@
+/// What kind of food a donation is. One per food.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Produce,
+    Bakery,
+    CookedMeal,
+    Dairy,
+    MeatFish,
+    Pantry,
+    Frozen,
+    Drinks,
+    Snacks,
+    BabyFood,
+    Other,
+}
+
+/// Diets a donor says the food is suitable for. A food can have any number of them.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DietaryTag {
+    Vegan,
+    Vegetarian,
+    Halal,
+    Kosher,
+    GlutenFree,
+    DairyFree,
+}
+
+/// The 14 allergens EU law (Regulation 1169/2011, Annex II) requires to be declared.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allergen {
+    Gluten,
+    Crustaceans,
+    Eggs,
+    Fish,
+    Peanuts,
+    Soybeans,
+    Milk,
+    TreeNuts,
+    Celery,
+    Mustard,
+    Sesame,
+    Sulphites,
+    Lupin,
+    Molluscs,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Produce => "produce",
+            Category::Bakery => "bakery",
+            Category::CookedMeal => "cooked_meal",
+            Category::Dairy => "dairy",
+            Category::MeatFish => "meat_fish",
+            Category::Pantry => "pantry",
+            Category::Frozen => "frozen",
+            Category::Drinks => "drinks",
+            Category::Snacks => "snacks",
+            Category::BabyFood => "baby_food",
+            Category::Other => "other",
+        }
+    }
+
+    pub fn parse(category: &str) -> Option<Self> {
+        match category {
+            "produce" => Some(Category::Produce),
+            "bakery" => Some(Category::Bakery),
+            "cooked_meal" => Some(Category::CookedMeal),
+            "dairy" => Some(Category::Dairy),
+            "meat_fish" => Some(Category::MeatFish),
+            "pantry" => Some(Category::Pantry),
+            "frozen" => Some(Category::Frozen),
+            "drinks" => Some(Category::Drinks),
+            "snacks" => Some(Category::Snacks),
+            "baby_food" => Some(Category::BabyFood),
+            "other" => Some(Category::Other),
+            _ => None,
+        }
+    }
+}
+
+impl DietaryTag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DietaryTag::Vegan => "vegan",
+            DietaryTag::Vegetarian => "vegetarian",
+            DietaryTag::Halal => "halal",
+            DietaryTag::Kosher => "kosher",
+            DietaryTag::GlutenFree => "gluten_free",
+            DietaryTag::DairyFree => "dairy_free",
+        }
+    }
+
+    pub fn parse(tag: &str) -> Option<Self> {
+        match tag {
+            "vegan" => Some(DietaryTag::Vegan),
+            "vegetarian" => Some(DietaryTag::Vegetarian),
+            "halal" => Some(DietaryTag::Halal),
+            "kosher" => Some(DietaryTag::Kosher),
+            "gluten_free" => Some(DietaryTag::GlutenFree),
+            "dairy_free" => Some(DietaryTag::DairyFree),
+            _ => None,
+        }
+    }
+}
+
+impl Allergen {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Allergen::Gluten => "gluten",
+            Allergen::Crustaceans => "crustaceans",
+            Allergen::Eggs => "eggs",
+            Allergen::Fish => "fish",
+            Allergen::Peanuts => "peanuts",
+            Allergen::Soybeans => "soybeans",
+            Allergen::Milk => "milk",
+            Allergen::TreeNuts => "tree_nuts",
+            Allergen::Celery => "celery",
+            Allergen::Mustard => "mustard",
+            Allergen::Sesame => "sesame",
+            Allergen::Sulphites => "sulphites",
+            Allergen::Lupin => "lupin",
+            Allergen::Molluscs => "molluscs",
+        }
+    }
+
+    pub fn parse(allergen: &str) -> Option<Self> {
+        match allergen {
+            "gluten" => Some(Allergen::Gluten),
+            "crustaceans" => Some(Allergen::Crustaceans),
+            "eggs" => Some(Allergen::Eggs),
+            "fish" => Some(Allergen::Fish),
+            "peanuts" => Some(Allergen::Peanuts),
+            "soybeans" => Some(Allergen::Soybeans),
+            "milk" => Some(Allergen::Milk),
+            "tree_nuts" => Some(Allergen::TreeNuts),
+            "celery" => Some(Allergen::Celery),
+            "mustard" => Some(Allergen::Mustard),
+            "sesame" => Some(Allergen::Sesame),
+            "sulphites" => Some(Allergen::Sulphites),
+            "lupin" => Some(Allergen::Lupin),
+            "molluscs" => Some(Allergen::Molluscs),
+            _ => None,
+        }
+    }
+}
+
+// query strings carry lists as "a,b,c"
+pub fn parse_list<T>(list: &str, parse: fn(&str) -> Option<T>) -> Result<Vec<T>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| parse(item).ok_or_else(|| format!("unknown value '{}'", item)))
+        .collect()
+}
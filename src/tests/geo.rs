@@ -0,0 +1,38 @@
+use crate::geo::{bounding_box, haversine_km, Gazetteer, Geocoder};
+
+fn in_box(lat: f64, lng: f64, center: (f64, f64), radius_km: f64) -> bool {
+    let area = bounding_box(center.0, center.1, radius_km);
+    (area.min_lat..=area.max_lat).contains(&lat) && area.lng_ranges.iter().any(|(min, max)| (*min..=*max).contains(&lng))
+}
+
+#[test]
+fn the_bounding_box_wraps_around_the_antimeridian() {
+    // fiji sits on the antimeridian, points a few km either side of it are close by
+    for (center, point) in [((-17.0, 179.98), (-17.0, -179.98)), ((-17.0, -179.98), (-17.0, 179.98))] {
+        assert!(haversine_km(center.0, center.1, point.0, point.1) < 5.0);
+        assert!(in_box(point.0, point.1, center, 5.0), "{:?} around {:?}", point, center);
+        assert!(!in_box(point.0, 0.0, center, 5.0));
+    }
+}
+
+#[test]
+fn the_bounding_box_takes_every_longitude_at_a_pole() {
+    assert!(in_box(89.99, -120.0, (89.99, 60.0), 5.0));
+}
+
+fn gazetteer(rows: &str) -> Gazetteer {
+    let path = std::env::temp_dir().join(format!("avanzo-gazetteer-{}.csv", std::process::id()));
+    std::fs::write(&path, format!("street,postcode,city,latitude,longitude\n{}", rows)).unwrap();
+    let gazetteer = Gazetteer::load(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(path).unwrap();
+    gazetteer
+}
+
+#[test]
+fn a_postcode_only_matches_as_whole_words() {
+    let gazetteer = gazetteer("via roma,00184,roma,41.9,12.49\nbaker street,NW1 6XE,london,51.52,-0.15\n");
+    assert!(gazetteer.geocode("piazza qualsiasi 3, 00184").is_ok());
+    assert!(gazetteer.geocode("221b something road, NW1 6XE").is_ok());
+    assert!(gazetteer.geocode("via lontana 1001845").is_err());
+    assert!(gazetteer.geocode("via lontana 00184 3").is_ok());
+}
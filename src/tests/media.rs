@@ -0,0 +1,63 @@
+use actix_web::{http::header, test};
+use serde_json::{json, Value};
+use sqlx::MySqlPool;
+
+use super::{token, user};
+
+const BOUNDARY: &str = "avanzo-test-boundary";
+
+fn png() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image::RgbImage::from_pixel(8, 8, image::Rgb([200, 80, 40]))
+        .write_to(&mut std::io::Cursor::new(&mut bytes), image::ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+fn upload_request(token: String, file: &[u8]) -> actix_web::dev::Request {
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"soup.png\"\r\nContent-Type: image/png\r\n\r\n",
+        BOUNDARY
+    ).into_bytes();
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    test::TestRequest::post()
+        .uri("/media")
+        .insert_header(("Authorization", token))
+        .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+        .set_payload(body)
+        .to_request()
+}
+
+fn picture_request(user_id: i32, token: String, key: &str) -> actix_web::dev::Request {
+    test::TestRequest::patch()
+        .uri(&format!("/users/{}/picture", user_id))
+        .insert_header(("Authorization", token))
+        .set_json(json!({ "profile_image_key": key }))
+        .to_request()
+}
+
+// the same file is stored once, but each user who uploaded it can use it
+#[sqlx::test]
+async fn everyone_who_uploaded_a_file_can_use_it(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let first = user(&pool, "first@example.com").await;
+    let second = user(&pool, "second@example.com").await;
+    let other = user(&pool, "other@example.com").await;
+
+    let mut keys = Vec::new();
+    for uploader in [first, second] {
+        let res = test::call_service(&app, upload_request(token(&pool, uploader).await, &png())).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = test::read_body_json(res).await;
+        keys.push(body["data"]["key"].as_str().unwrap().to_string());
+    }
+    assert_eq!(keys[0], keys[1]);
+
+    for uploader in [first, second] {
+        let res = test::call_service(&app, picture_request(uploader, token(&pool, uploader).await, &keys[0])).await;
+        assert_eq!(res.status(), 200);
+    }
+    let res = test::call_service(&app, picture_request(other, token(&pool, other).await, &keys[0])).await;
+    assert_eq!(res.status(), 403);
+}
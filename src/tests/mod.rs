@@ -0,0 +1,84 @@
+// end to end tests through the http layer. #[sqlx::test] gives each test its own fresh database with
+// the migrations applied, so DATABASE_URL has to point at a server the tests may create databases on
+use chrono::{Duration as ChronoDuration, Utc};
+use serde_json::{json, Value};
+use sqlx::MySqlPool;
+
+use crate::auth::{generate_token, hash_token, issue_access_token, load_token_secret};
+use crate::db::{create_reservation, create_session, insert_food, FoodDetail};
+use crate::reservation::ReservationPolicy;
+
+// the app the way main builds it, minus cors
+macro_rules! test_app {
+    ($pool:expr) => {
+        actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(actix_web::web::Data::new($pool.clone()))
+                .app_data(actix_web::web::Data::new(crate::throttle::LoginThrottle::new(Box::new(crate::throttle::MemoryStore::default()))))
+                .app_data(actix_web::web::Data::from(std::sync::Arc::new(crate::geo::Gazetteer::default()) as std::sync::Arc<dyn crate::geo::Geocoder>))
+                .app_data(actix_web::web::Data::from(std::sync::Arc::new(crate::media::LocalStore::new(std::env::temp_dir().join("avanzo-test-media")).unwrap()) as std::sync::Arc<dyn crate::media::MediaStore>))
+                .app_data(actix_web::web::Data::new(crate::reservation::ReservationPolicy::from_env()))
+                .app_data(actix_web::web::Data::new(crate::throttle::RateLimiter::new(crate::throttle::MAX_MESSAGES_PER_MINUTE, std::time::Duration::from_secs(60))))
+                .app_data(actix_web::web::Data::new(crate::feed::FeedBroadcaster::default()))
+                .configure(crate::routes)
+        )
+    };
+}
+
+mod geo;
+mod media;
+mod ownership;
+mod password;
+mod reservation_rules;
+mod reservations;
+mod waitlist;
+
+// a verified user, emails have to be unique within a test
+pub async fn user(pool: &MySqlPool, email: &str) -> i32 {
+    sqlx::query("INSERT INTO users (email, password_hash, first_name, email_verified) VALUES (?, 'not a real hash', 'Test', 1)")
+        .bind(email)
+        .execute(pool)
+        .await
+        .unwrap()
+        .last_insert_id() as i32
+}
+
+// an access token with a live session behind it
+pub async fn token(pool: &MySqlPool, user_id: i32) -> String {
+    std::env::set_var("TOKEN_SECRET", "test secret");
+    load_token_secret().unwrap();
+    let session_id = create_session(pool, user_id, &hash_token(&generate_token())).await.unwrap();
+    format!("Bearer {}", issue_access_token(user_id, session_id))
+}
+
+// what a client would send to POST /foods, picked up in an hour
+pub fn food_body(portions: i32) -> Value {
+    let start = Utc::now() + ChronoDuration::hours(1);
+    json!({
+        "title": "soup",
+        "description": "a pot of soup",
+        "is_free": true,
+        "pickup_start": start.to_rfc3339(),
+        "pickup_end": (start + ChronoDuration::hours(2)).to_rfc3339(),
+        "quantity": portions,
+        "pickup_address": "via roma 1",
+        "user_id": 0
+    })
+}
+
+pub async fn food(pool: &MySqlPool, owner: i32, portions: i32) -> i32 {
+    let mut food: FoodDetail = serde_json::from_value(food_body(portions)).unwrap();
+    food.user_id = owner;
+    insert_food(pool, &food).await.unwrap() as i32
+}
+
+// a reservation of one portion, straight through the db like POST /users/{id}/reserve does it
+pub async fn reservation(pool: &MySqlPool, reserver: i32, food_id: i32) -> i32 {
+    create_reservation(pool, &ReservationPolicy::from_env(), reserver, food_id, 1).await.unwrap();
+    sqlx::query_scalar("SELECT id FROM reservations WHERE user_id = ? AND food_id = ?")
+        .bind(reserver)
+        .bind(food_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
@@ -0,0 +1,47 @@
+use actix_web::test;
+use sqlx::MySqlPool;
+
+use super::{food, food_body, token, user};
+
+#[sqlx::test]
+async fn only_the_donor_can_delete_a_donation(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let other = user(&pool, "other@example.com").await;
+    let food_id = food(&pool, donor, 1).await;
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/foods/{}", food_id))
+        .insert_header(("Authorization", token(&pool, other).await))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+    let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM foods WHERE id = ?").bind(food_id).fetch_one(&pool).await.unwrap();
+    assert_eq!(left, 1);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/foods/{}", food_id))
+        .insert_header(("Authorization", token(&pool, donor).await))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+}
+
+#[sqlx::test]
+async fn only_the_donor_can_edit_a_donation(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let other = user(&pool, "other@example.com").await;
+    let food_id = food(&pool, donor, 1).await;
+
+    let mut body = food_body(1);
+    body["food_id"] = food_id.into();
+    body["title"] = "mine now".into();
+    let req = test::TestRequest::patch()
+        .uri("/donations")
+        .insert_header(("Authorization", token(&pool, other).await))
+        .set_json(&body)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+    let title: Option<String> = sqlx::query_scalar("SELECT title FROM foods WHERE id = ?").bind(food_id).fetch_one(&pool).await.unwrap();
+    assert_eq!(title.as_deref(), Some("soup"));
+}
@@ -0,0 +1,39 @@
+use actix_web::test;
+use serde_json::{json, Value};
+use sqlx::MySqlPool;
+
+use super::user;
+
+fn forgot_request(email: &str) -> actix_web::dev::Request {
+    test::TestRequest::post()
+        .uri("/password/forgot")
+        .peer_addr("10.0.0.1:4000".parse().unwrap())
+        .set_json(json!({ "email": email }))
+        .to_request()
+}
+
+// the answer can't tell anyone which emails have an account
+#[sqlx::test]
+async fn forgot_password_answers_the_same_for_unknown_emails(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    user(&pool, "known@example.com").await;
+
+    let known = test::call_service(&app, forgot_request("known@example.com")).await;
+    assert_eq!(known.status(), 200);
+    let known: Value = test::read_body_json(known).await;
+    let unknown = test::call_service(&app, forgot_request("unknown@example.com")).await;
+    assert_eq!(unknown.status(), 200);
+    let unknown: Value = test::read_body_json(unknown).await;
+    assert_eq!(known, unknown);
+}
+
+#[sqlx::test]
+async fn forgot_password_is_rate_limited_per_address(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let mut statuses = Vec::new();
+    for i in 0..crate::throttle::MAX_MESSAGES_PER_MINUTE + 1 {
+        let res = test::call_service(&app, forgot_request(&format!("someone{}@example.com", i))).await;
+        statuses.push(res.status().as_u16());
+    }
+    assert_eq!(statuses.last(), Some(&429), "{:?}", statuses);
+}
@@ -0,0 +1,89 @@
+use crate::reservation::{Actor, ReservationPolicy, ReservationStatus, ReserverStanding};
+use crate::reservation::ReservationStatus::*;
+
+const STATUSES: [ReservationStatus; 8] = [Pending, Requested, Confirmed, PickedUp, Cancelled, NoShow, Expired, Declined];
+const ACTORS: [Actor; 3] = [Actor::Donor, Actor::Receiver, Actor::System];
+
+// every change that may happen and who may make it, anything not listed here has to be refused
+const ALLOWED: &[(ReservationStatus, ReservationStatus, &[Actor])] = &[
+    (Pending, Confirmed, &[Actor::Donor]),
+    (Pending, Declined, &[Actor::Donor, Actor::System]),
+    (Pending, Cancelled, &[Actor::Donor, Actor::Receiver, Actor::System]),
+    (Pending, Expired, &[Actor::System]),
+    (Requested, Confirmed, &[Actor::Donor]),
+    (Requested, Cancelled, &[Actor::Donor, Actor::Receiver, Actor::System]),
+    (Requested, Expired, &[Actor::System]),
+    (Confirmed, PickedUp, &[Actor::Donor]),
+    (Confirmed, Cancelled, &[Actor::Donor, Actor::Receiver, Actor::System]),
+    (Confirmed, NoShow, &[Actor::Donor, Actor::System]),
+];
+
+#[test]
+fn every_status_change_is_allowed_only_for_the_right_actor() {
+    for from in STATUSES {
+        for to in STATUSES {
+            let listed = ALLOWED.iter().find(|(f, t, _)| *f == from && *t == to);
+            assert_eq!(from.can_become(to), listed.is_some(), "{} -> {}", from.as_str(), to.as_str());
+            for actor in ACTORS {
+                let expected = listed.is_some_and(|(_, _, actors)| actors.contains(&actor));
+                let allowed = from.can_become(to) && to.allowed_for(actor);
+                assert_eq!(allowed, expected, "{} -> {} by {}", from.as_str(), to.as_str(), actor.as_str());
+            }
+        }
+    }
+}
+
+#[test]
+fn final_statuses_never_change() {
+    for from in STATUSES.into_iter().filter(|status| !status.is_open()) {
+        for to in STATUSES {
+            assert!(!from.can_become(to), "{} -> {}", from.as_str(), to.as_str());
+        }
+    }
+}
+
+const POLICY: ReservationPolicy = ReservationPolicy {
+    max_active: 1,
+    max_active_verified: 3,
+    max_active_organization: 10,
+    no_show_cooldown_hours: 48,
+};
+
+fn standing(verified: bool, organization: bool, active: i64, secs_since_no_show: Option<i64>) -> ReserverStanding {
+    ReserverStanding { verified, organization, active, secs_since_no_show }
+}
+
+#[test]
+fn the_limit_depends_on_who_is_reserving() {
+    assert_eq!(POLICY.limit_for(&standing(false, false, 0, None)), 1);
+    assert_eq!(POLICY.limit_for(&standing(true, false, 0, None)), 3);
+    // an organization gets its own limit whether or not it is verified
+    assert_eq!(POLICY.limit_for(&standing(false, true, 0, None)), 10);
+    assert_eq!(POLICY.limit_for(&standing(true, true, 0, None)), 10);
+
+    for (verified, organization, limit) in [(false, false, 1), (true, false, 3), (false, true, 10)] {
+        assert!(POLICY.check(&standing(verified, organization, limit - 1, None)).is_ok());
+        assert_eq!(
+            POLICY.check(&standing(verified, organization, limit, None)),
+            Err(format!("you can have at most {} open reservations", limit))
+        );
+    }
+}
+
+#[test]
+fn a_no_show_blocks_reserving_until_the_cooldown_is_over() {
+    let cooldown_secs = 48 * 3600;
+    assert_eq!(
+        POLICY.check(&standing(true, false, 0, Some(0))),
+        Err(format!("you missed a pickup recently, you can reserve again in 48 hours"))
+    );
+    // a part of an hour left still counts as an hour
+    assert_eq!(
+        POLICY.check(&standing(true, false, 0, Some(cooldown_secs - 1))),
+        Err(format!("you missed a pickup recently, you can reserve again in 1 hours"))
+    );
+    assert!(POLICY.check(&standing(true, false, 0, Some(cooldown_secs))).is_ok());
+    assert!(POLICY.check(&standing(true, false, 0, Some(cooldown_secs + 1))).is_ok());
+    // the cooldown is checked before the limit
+    assert!(POLICY.check(&standing(true, false, 3, Some(0))).unwrap_err().starts_with("you missed a pickup"));
+}
@@ -0,0 +1,133 @@
+use actix_web::test;
+use futures_util::future::join_all;
+use serde_json::json;
+use sqlx::MySqlPool;
+
+use super::{food, reservation, token, user};
+
+// everyone goes for the one portion at once, the row lock on the food has to let exactly one through
+#[sqlx::test]
+async fn parallel_reservations_claim_a_single_portion_once(pool: MySqlPool) {
+    const RESERVERS: usize = 8;
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let food_id = food(&pool, donor, 1).await;
+
+    let mut requests = Vec::new();
+    for i in 0..RESERVERS {
+        let reserver = user(&pool, &format!("reserver{}@example.com", i)).await;
+        requests.push(
+            test::TestRequest::post()
+                .uri(&format!("/users/{}/reserve", reserver))
+                .insert_header(("Authorization", token(&pool, reserver).await))
+                .set_json(json!({ "food_id": food_id }))
+                .to_request(),
+        );
+    }
+    let responses = join_all(requests.into_iter().map(|req| test::call_service(&app, req))).await;
+
+    let statuses: Vec<u16> = responses.iter().map(|res| res.status().as_u16()).collect();
+    assert_eq!(statuses.iter().filter(|status| **status == 200).count(), 1, "{:?}", statuses);
+    assert_eq!(statuses.iter().filter(|status| **status == 409).count(), RESERVERS - 1, "{:?}", statuses);
+
+    let remaining: i32 = sqlx::query_scalar("SELECT quantity_remaining FROM foods WHERE id = ?").bind(food_id).fetch_one(&pool).await.unwrap();
+    assert_eq!(remaining, 0);
+    let held: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reservations WHERE food_id = ? AND status = 'requested'")
+        .bind(food_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(held, 1);
+}
+
+async fn status_of(pool: &MySqlPool, reservation_id: i32) -> String {
+    sqlx::query_scalar("SELECT status FROM reservations WHERE id = ?").bind(reservation_id).fetch_one(pool).await.unwrap()
+}
+
+fn cancel_request(reservation_id: i32, token: String) -> actix_web::dev::Request {
+    test::TestRequest::post()
+        .uri(&format!("/reservations/{}/cancel", reservation_id))
+        .insert_header(("Authorization", token))
+        .set_json(json!({ "reason": "plans changed" }))
+        .to_request()
+}
+
+#[sqlx::test]
+async fn cancelling_one_reservation_leaves_the_others_on_the_food(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let food_id = food(&pool, donor, 3).await;
+    let mut reservations = Vec::new();
+    for i in 0..3 {
+        let reserver = user(&pool, &format!("reserver{}@example.com", i)).await;
+        reservations.push((reserver, reservation(&pool, reserver, food_id).await));
+    }
+
+    let (reserver, cancelled) = reservations[1];
+    let res = test::call_service(&app, cancel_request(cancelled, token(&pool, reserver).await)).await;
+    assert_eq!(res.status(), 200);
+
+    assert_eq!(status_of(&pool, cancelled).await, "cancelled");
+    for (_, kept) in [reservations[0], reservations[2]] {
+        assert_eq!(status_of(&pool, kept).await, "requested");
+    }
+    let remaining: i32 = sqlx::query_scalar("SELECT quantity_remaining FROM foods WHERE id = ?").bind(food_id).fetch_one(&pool).await.unwrap();
+    assert_eq!(remaining, 1);
+}
+
+#[sqlx::test]
+async fn nobody_else_can_cancel_a_reservation(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let reserver = user(&pool, "reserver@example.com").await;
+    let other = user(&pool, "other@example.com").await;
+    let food_id = food(&pool, donor, 1).await;
+    let reservation_id = reservation(&pool, reserver, food_id).await;
+
+    let res = test::call_service(&app, cancel_request(reservation_id, token(&pool, other).await)).await;
+    assert_eq!(res.status(), 403);
+    assert_eq!(status_of(&pool, reservation_id).await, "requested");
+}
+
+#[sqlx::test]
+async fn finished_reservations_can_not_be_cancelled(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let food_id = food(&pool, donor, 2).await;
+
+    for (i, finished) in ["picked_up", "expired"].into_iter().enumerate() {
+        let reserver = user(&pool, &format!("reserver{}@example.com", i)).await;
+        let reservation_id = reservation(&pool, reserver, food_id).await;
+        sqlx::query("UPDATE reservations SET status = ? WHERE id = ?").bind(finished).bind(reservation_id).execute(&pool).await.unwrap();
+
+        let res = test::call_service(&app, cancel_request(reservation_id, token(&pool, reserver).await)).await;
+        assert_eq!(res.status(), 409, "{}", finished);
+        assert_eq!(status_of(&pool, reservation_id).await, finished);
+    }
+}
+
+#[sqlx::test]
+async fn food_can_not_be_reserved_after_its_pickup_window(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let reserver = user(&pool, "reserver@example.com").await;
+    let food_id = food(&pool, donor, 2).await;
+    // the window closed an hour ago, the food itself is still good
+    sqlx::query("UPDATE foods SET pickup_start = NOW() - INTERVAL 3 HOUR, pickup_end = NOW() - INTERVAL 1 HOUR WHERE id = ?")
+        .bind(food_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/users/{}/reserve", reserver))
+        .insert_header(("Authorization", token(&pool, reserver).await))
+        .set_json(json!({ "food_id": food_id }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 409);
+
+    let remaining: i32 = sqlx::query_scalar("SELECT quantity_remaining FROM foods WHERE id = ?").bind(food_id).fetch_one(&pool).await.unwrap();
+    assert_eq!(remaining, 2);
+    let reservations: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reservations WHERE food_id = ?").bind(food_id).fetch_one(&pool).await.unwrap();
+    assert_eq!(reservations, 0);
+}
@@ -0,0 +1,86 @@
+use actix_web::test;
+use serde_json::json;
+use sqlx::MySqlPool;
+
+use super::{food, token, user};
+
+async fn reserve(pool: &MySqlPool, reserver: i32, food_id: i32, quantity: i32) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(&format!("/users/{}/reserve", reserver))
+        .insert_header(("Authorization", token(pool, reserver).await))
+        .set_json(json!({ "food_id": food_id, "quantity": quantity }))
+}
+
+async fn join(pool: &MySqlPool, user_id: i32, food_id: i32, quantity: i32) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(&format!("/foods/{}/waitlist", food_id))
+        .insert_header(("Authorization", token(pool, user_id).await))
+        .set_json(json!({ "quantity": quantity }))
+}
+
+async fn remaining(pool: &MySqlPool, food_id: i32) -> i32 {
+    sqlx::query_scalar("SELECT quantity_remaining FROM foods WHERE id = ?").bind(food_id).fetch_one(pool).await.unwrap()
+}
+
+// the one portion left is too few for the head of the line, but it isn't up for grabs either
+#[sqlx::test]
+async fn nobody_reserves_past_the_waitlist(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let first = user(&pool, "first@example.com").await;
+    let waiting = user(&pool, "waiting@example.com").await;
+    let late = user(&pool, "late@example.com").await;
+    let food_id = food(&pool, donor, 3).await;
+
+    assert_eq!(test::call_service(&app, reserve(&pool, first, food_id, 2).await.to_request()).await.status(), 200);
+    assert_eq!(test::call_service(&app, join(&pool, waiting, food_id, 2).await.to_request()).await.status(), 200);
+
+    assert_eq!(test::call_service(&app, reserve(&pool, late, food_id, 1).await.to_request()).await.status(), 409);
+    assert_eq!(remaining(&pool, food_id).await, 1);
+    // the line is where the late one belongs now
+    assert_eq!(test::call_service(&app, join(&pool, late, food_id, 1).await.to_request()).await.status(), 200);
+}
+
+// an offer claims the portions the person queued for and nothing more
+#[sqlx::test]
+async fn an_offer_holds_only_the_queued_portions(pool: MySqlPool) {
+    let app = test_app!(pool).await;
+    let donor = user(&pool, "donor@example.com").await;
+    let first = user(&pool, "first@example.com").await;
+    let waiting = user(&pool, "waiting@example.com").await;
+    let late = user(&pool, "late@example.com").await;
+    let food_id = food(&pool, donor, 3).await;
+
+    assert_eq!(test::call_service(&app, reserve(&pool, first, food_id, 3).await.to_request()).await.status(), 200);
+    assert_eq!(test::call_service(&app, join(&pool, waiting, food_id, 1).await.to_request()).await.status(), 200);
+
+    let reservation_id: i32 = sqlx::query_scalar("SELECT id FROM reservations WHERE user_id = ? AND food_id = ?")
+        .bind(first)
+        .bind(food_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let req = test::TestRequest::post()
+        .uri(&format!("/reservations/{}/cancel", reservation_id))
+        .insert_header(("Authorization", token(&pool, first).await))
+        .set_json(json!({}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    let offer: String = sqlx::query_scalar("SELECT status FROM food_waitlist WHERE food_id = ? AND user_id = ?")
+        .bind(food_id)
+        .bind(waiting)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(offer, "offered");
+    assert_eq!(remaining(&pool, food_id).await, 2);
+
+    assert_eq!(test::call_service(&app, reserve(&pool, late, food_id, 2).await.to_request()).await.status(), 200);
+    let req = test::TestRequest::post()
+        .uri(&format!("/foods/{}/waitlist/accept", food_id))
+        .insert_header(("Authorization", token(&pool, waiting).await))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+    assert_eq!(remaining(&pool, food_id).await, 0);
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+// failures allowed before a key gets locked, an ip gets more room since many users can share one
+pub const MAX_ACCOUNT_FAILURES: u32 = 5;
+pub const MAX_IP_FAILURES: u32 = 20;
+
+const BASE_LOCKOUT: Duration = Duration::from_secs(60);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+// failures older than this are forgotten, lockout history after a quiet day
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+const LOCKOUT_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+// how often the in-memory store sweeps out quiet keys
+const PRUNE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// messages one user can send across all their threads
+pub const MAX_MESSAGES_PER_MINUTE: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct AttemptState {
+    pub failures: u32,
+    pub lockouts: u32,
+    pub locked_until: Option<SystemTime>,
+    pub last_failure: SystemTime,
+}
+
+/// Where failed attempts are kept. The in-memory store only sees one process,
+/// implement this over a shared store when running several instances.
+pub trait AttemptStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<AttemptState>;
+    // read, change and write back one key as a single step, so parallel failures all get counted
+    fn update(&self, key: &str, change: &mut dyn FnMut(Option<AttemptState>) -> AttemptState);
+    fn remove(&self, key: &str);
+    fn entries(&self) -> Vec<(String, AttemptState)>;
+}
+
+pub struct MemoryStore {
+    attempts: Mutex<HashMap<String, AttemptState>>,
+    last_prune: Mutex<SystemTime>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore { attempts: Mutex::new(HashMap::new()), last_prune: Mutex::new(SystemTime::now()) }
+    }
+}
+
+// drops what has been quiet long enough so the map doesn't grow forever
+fn prune(attempts: &mut HashMap<String, AttemptState>, now: SystemTime) {
+    attempts.retain(|_, state| elapsed(state.last_failure, now) < LOCKOUT_MEMORY);
+}
+
+impl AttemptStore for MemoryStore {
+    fn get(&self, key: &str) -> Option<AttemptState> {
+        self.attempts.lock().unwrap().get(key).cloned()
+    }
+
+    fn update(&self, key: &str, change: &mut dyn FnMut(Option<AttemptState>) -> AttemptState) {
+        let now = SystemTime::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        let state = change(attempts.remove(key));
+        attempts.insert(key.to_string(), state);
+
+        // sprayed emails or addresses only ever write, so the sweep has to happen here too
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if elapsed(*last_prune, now) >= PRUNE_INTERVAL {
+            prune(&mut attempts, now);
+            *last_prune = now;
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+
+    fn entries(&self) -> Vec<(String, AttemptState)> {
+        let mut attempts = self.attempts.lock().unwrap();
+        prune(&mut attempts, SystemTime::now());
+        attempts.iter().map(|(key, state)| (key.clone(), state.clone())).collect()
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct LockoutInfo {
+    pub key: String,
+    pub failures: u32,
+    pub lockouts: u32,
+    pub locked_for_secs: u64,
+}
+
+pub struct LoginThrottle {
+    store: Box<dyn AttemptStore>,
+}
+
+fn elapsed(since: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(since).unwrap_or_default()
+}
+
+fn remaining(state: &AttemptState, now: SystemTime) -> Option<Duration> {
+    state.locked_until.and_then(|until| until.duration_since(now).ok())
+}
+
+pub fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+pub fn ip_key(addr: &str) -> String {
+    format!("ip:{}", addr)
+}
+
+impl LoginThrottle {
+    pub fn new(store: Box<dyn AttemptStore>) -> Self {
+        LoginThrottle { store }
+    }
+
+    // how long the caller still has to wait, if any of the keys is locked
+    pub fn retry_after(&self, keys: &[&str]) -> Option<Duration> {
+        let now = SystemTime::now();
+        keys.iter()
+            .filter_map(|key| self.store.get(key))
+            .filter_map(|state| remaining(&state, now))
+            .max()
+    }
+
+    // returns true when this failure started a new lockout. every lockout in a row doubles the wait
+    pub fn record_failure(&self, key: &str, max_failures: u32) -> bool {
+        let now = SystemTime::now();
+        let mut locked = false;
+        self.store.update(key, &mut |state| {
+            let mut state = state.unwrap_or(AttemptState { failures: 0, lockouts: 0, locked_until: None, last_failure: now });
+            if elapsed(state.last_failure, now) > LOCKOUT_MEMORY {
+                state.lockouts = 0;
+            }
+            if elapsed(state.last_failure, now) > FAILURE_WINDOW {
+                state.failures = 0;
+            }
+
+            state.failures += 1;
+            state.last_failure = now;
+
+            locked = state.failures >= max_failures;
+            if locked {
+                let backoff = BASE_LOCKOUT.saturating_mul(2u32.saturating_pow(state.lockouts));
+                state.locked_until = Some(now + backoff.min(MAX_LOCKOUT));
+                state.lockouts += 1;
+                state.failures = 0;
+            }
+            state
+        });
+        locked
+    }
+
+    pub fn record_success(&self, key: &str) {
+        self.store.remove(key);
+    }
+
+    pub fn clear(&self, key: &str) {
+        self.store.remove(key);
+    }
+
+    pub fn lockouts(&self) -> Vec<LockoutInfo> {
+        let now = SystemTime::now();
+        self.store.entries()
+            .into_iter()
+            .map(|(key, state)| LockoutInfo {
+                key,
+                failures: state.failures,
+                lockouts: state.lockouts,
+                locked_for_secs: remaining(&state, now).map(|d| d.as_secs()).unwrap_or(0),
+            })
+            .collect()
+    }
+}
+
+/// Caps how often a key can do something within a sliding window, e.g. a user sending messages.
+/// Kept in memory like the login counters, so it is per instance.
+pub struct RateLimiter {
+    max: usize,
+    window: Duration,
+    hits: Mutex<HashMap<String, Vec<SystemTime>>>,
+    last_prune: Mutex<SystemTime>,
+}
+
+impl RateLimiter {
+    pub fn new(max: usize, window: Duration) -> Self {
+        RateLimiter { max, window, hits: Mutex::new(HashMap::new()), last_prune: Mutex::new(SystemTime::now()) }
+    }
+
+    // counts the hit when it is allowed, otherwise says how long until the oldest one leaves the window
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = SystemTime::now();
+        let mut hits = self.hits.lock().unwrap();
+        // forget keys that have gone quiet so the map doesn't grow forever, every so often rather than on every hit
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if elapsed(*last_prune, now) >= PRUNE_INTERVAL {
+            hits.retain(|_, times| times.last().is_some_and(|last| elapsed(*last, now) < self.window));
+            *last_prune = now;
+        }
+        drop(last_prune);
+
+        let times = hits.entry(key.to_string()).or_default();
+        times.retain(|time| elapsed(*time, now) < self.window);
+        if times.len() >= self.max {
+            return Err(self.window.saturating_sub(elapsed(times[0], now)));
+        }
+        times.push(now);
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+use sqlx::MySqlPool;
+
+use crate::db::promote_waitlist;
+use crate::functions::send_waitlist_offer_mail;
+
+// how long the person at the front of the line has to accept before the offer moves on
+pub const WAITLIST_OFFER_MINUTES: i32 = 30;
+
+/// Offers the portions that just freed up to whoever is first in line and emails them.
+/// Failures are only logged, the release that got us here has already gone through.
+pub async fn offer_next(pool: &MySqlPool, food_id: i32) {
+    match promote_waitlist(pool, food_id).await {
+        Ok(offers) => {
+            for offer in offers {
+                let title = offer.food_title.unwrap_or_default();
+                if let Err(err) = send_waitlist_offer_mail(&offer.email, &title, food_id, WAITLIST_OFFER_MINUTES).await {
+                    log::warn!("couldn't send waitlist offer mail: {}", err);
+                }
+            }
+        }
+        Err(err) => log::error!("couldn't promote waitlist for food {}: {}", food_id, err)
+    }
+}